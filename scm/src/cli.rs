@@ -0,0 +1,824 @@
+//! The `scm` command line: argument parsing and output for each command.
+
+use std::env;
+use std::fs;
+
+use crate::*;
+
+// ---------------------------------------------------------
+// MAIN
+// ---------------------------------------------------------
+
+/// Run one scm command; `args` excludes the program name.
+pub fn run(args: Vec<String>) -> Result<()> {
+    let mut args = args.into_iter();
+    let command = args.next();
+
+    // Work on the repository at or above the current directory; path
+    // arguments are mapped back through user_path and arg_key
+    // init and clone open the repository they make themselves
+    let cwd = env::current_dir()?;
+    let repo = match find_repo_root(&cwd) {
+        _ if matches!(command.as_deref(), None | Some("init" | "clone")) => None,
+        Some(_) => Some(Repository::open(&cwd, Identity::default())?),
+        None if command.as_deref() == Some("verify-proof") => None,
+        None => return Err(ScmError::NotARepo),
+    };
+
+    let open = || repo.as_ref().ok_or(ScmError::NotARepo);
+    let dispatch = || match command.as_deref() {
+        Some("init") => init_cmd(args.collect()),
+        Some("commit") => commit(open()?, args.collect()),
+        Some("revert") => revert_cmd(open()?, args.collect()),
+        Some("restore") => restore_cmd(open()?, args.collect()),
+        Some("log") => log_cmd(open()?, args.collect()),
+        Some("diff") => diff_cmd(open()?, args.collect()),
+        Some("reset") => reset_cmd(open()?),
+        Some("check-ignore") => check_ignore_cmd(open()?, args.collect()),
+        Some("add") => add_cmd(open()?, args.collect()),
+        Some("rm") => rm_cmd(open()?, args.collect()),
+        Some("status") => status_cmd(open()?),
+        Some("config") => config_cmd(open()?, args.collect()),
+        Some("fsck") | Some("verify") => fsck_cmd(open()?),
+        Some("key") => key_cmd(open()?, args.collect()),
+        Some("branch") => branch_cmd(open()?, args.collect()),
+        Some("checkout") => checkout_cmd(open()?, args.collect()),
+        Some("merge") => merge_cmd(open()?, args.collect()),
+        Some("tag") => tag_cmd(open()?, args.collect()),
+        Some("prove") => prove_cmd(open()?, args.collect()),
+        Some("verify-proof") => verify_proof_cmd(repo.as_ref(), args.collect()),
+        Some("clone") => clone_cmd(args.collect()),
+        Some("push") => push_cmd(open()?, args.collect()),
+        Some("pull") => pull_cmd(open()?, args.collect()),
+        Some("bundle") => bundle_cmd(open()?, args.collect()),
+        Some("gc") => gc_cmd(open()?, args.collect()),
+        Some(cmd) => Err(ScmError::Usage(format!("Unknown command: {cmd}\n{USAGE}"))),
+        None => Err(ScmError::Usage(USAGE.into())),
+    };
+
+    let (result, notices) = match &repo {
+        Some(repo) => (repo.enter(dispatch), repo.take_notices()),
+        None => collect_notices(dispatch),
+    };
+    for notice in notices {
+        eprintln!("{notice}");
+    }
+    result
+}
+
+const USAGE: &str = "Usage: scm <command> [<args>]
+  init, add, rm, status, commit, log, diff, branch, checkout, merge, revert, restore, reset,
+  tag, clone, push, pull, bundle, prove, verify-proof, check-ignore, config, fsck, gc, key";
+
+// ---------------------------------------------------------
+// COMMAND: COMMIT
+// ---------------------------------------------------------
+
+fn commit(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let message = match args.iter().position(|a| a == "-m" || a == "--message") {
+        Some(i) if i + 1 < args.len() => args[i + 1].clone(),
+        _ => match repo.merge_message()? {
+            Some(message) => message,
+            None => return Err(ScmError::Usage("Usage: scm commit -m <message>".into())),
+        },
+    };
+
+    match repo.commit(&message)? {
+        Some(id) => println!("Committed as {}", id.short()),
+        None => println!("Nothing to commit."),
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: REVERT
+// ---------------------------------------------------------
+
+fn revert_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    if args.iter().any(|a| a == "--abort") {
+        repo.abort_merge()?;
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let target = repo.resolve(args.first().map(|s| s.as_str()).unwrap_or("HEAD"))?;
+
+    match repo.revert(target.as_str())? {
+        RevertOutcome::NothingToRevert => {
+            println!("Nothing to revert; HEAD does not contain the changes of {}.", target.short());
+        }
+        RevertOutcome::Reverted(id) => println!("Reverted {} as {}", target.short(), id.short()),
+        RevertOutcome::Conflicts(conflicts) => {
+            for conflict in &conflicts {
+                println!("{conflict}");
+            }
+            return Err(ScmError::Conflict(
+                "Revert has conflicts; fix them, `scm add` them, then `scm commit`.".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: RESTORE
+// ---------------------------------------------------------
+
+fn restore_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let usage = || ScmError::Usage("Usage: scm restore [--keep] [--force] <path>... [--from <commit>]".into());
+
+    let mut from = "HEAD".to_string();
+    let mut options = RestoreOptions::default();
+    let mut paths = Vec::new();
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
+        if arg == "--from" || arg == "--source" {
+            let Some(rev) = it.next() else {
+                return Err(usage());
+            };
+            from = rev;
+        } else if arg == "--keep" {
+            options.keep = true;
+        } else if arg == "--force" {
+            options.force = true;
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(usage());
+    }
+
+    let keys: Vec<String> = paths.iter().map(|p| arg_key(p)).collect();
+    let (restored, removed) = repo.restore(&from, &keys.iter().map(|k| k.as_str()).collect::<Vec<_>>(), options)?;
+    let id = repo.resolve(&from)?;
+    match removed {
+        0 => println!("Restored {restored} file(s) from {}", id.short()),
+        n => println!("Restored {restored} file(s) from {} and removed {n}", id.short()),
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: LOG
+// ---------------------------------------------------------
+
+fn log_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let oneline = args.iter().any(|a| a == "--oneline");
+
+    let log = repo.log(args.iter().find(|a| !a.starts_with("--")).map(|s| s.as_str()))?;
+    if log.is_empty() {
+        println!("No commits yet.");
+        return Ok(());
+    }
+
+    for (n, (id, meta)) in log.iter().enumerate() {
+        let subject = meta.message.lines().next().unwrap_or("");
+
+        if oneline {
+            println!("{} {subject}", id.short());
+            continue;
+        }
+
+        if n > 0 {
+            println!();
+        }
+        println!("commit {id}");
+        match meta.parents.as_slice() {
+            [] => {}
+            [parent] => println!("Parent: {parent}"),
+            parents => {
+                let short: Vec<&str> = parents.iter().map(|p| short_id(p)).collect();
+                println!("Merge:  {}", short.join(" "));
+            }
+        }
+        if !meta.author.is_empty() {
+            println!("Author: {}", meta.author);
+        }
+        if meta.timestamp > 0 {
+            println!("Date:   {}", format_timestamp(meta.timestamp));
+        }
+        if !meta.message.is_empty() {
+            println!();
+            for line in meta.message.lines() {
+                println!("    {line}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: CONFIG
+// ---------------------------------------------------------
+
+fn config_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    match args.as_slice() {
+        [] => {
+            for (k, v) in &repo.config()? {
+                println!("{k} = {v}");
+            }
+        }
+        [key] => match repo.config()?.get(key) {
+            Some(v) => println!("{v}"),
+            None => return Err(ScmError::NotFound(format!("{key} is not set"))),
+        },
+        [key, value] => repo.set_config(key, value)?,
+        _ => return Err(ScmError::Usage("Usage: scm config [<key> [<value>]]".into())),
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: DIFF
+// ---------------------------------------------------------
+
+fn diff_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    // Old side is always a commit; new side is a commit or the working tree
+    let (old, new) = match args.as_slice() {
+        [] => {
+            if repo.head()?.is_none() {
+                return Err(ScmError::NotFound("No commits found.".into()));
+            }
+            ("HEAD", None)
+        }
+        [a] => (a.as_str(), None),
+        [a, b] => (a.as_str(), Some(b.as_str())),
+        _ => {
+            return Err(ScmError::Usage("Usage: scm diff [<commitA> [<commitB>]]".into()));
+        }
+    };
+
+    for file in repo.diff(old, new)? {
+        print!("{file}");
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: RESET
+// ---------------------------------------------------------
+
+fn reset_cmd(repo: &Repository) -> Result<()> {
+    // Only HEAD moves; the commit and its objects stay in the store and
+    // can still be checked out by ID.
+    let removed = repo.reset()?;
+    println!("Removed commit {}", removed.short());
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: CHECK-IGNORE
+// ---------------------------------------------------------
+
+fn check_ignore_cmd(repo: &Repository, paths: Vec<String>) -> Result<()> {
+    if paths.is_empty() {
+        return Err(ScmError::Usage("Usage: scm check-ignore <path>...".into()));
+    }
+
+    for arg in &paths {
+        let is_dir = arg.ends_with('/') || user_path(arg).is_dir();
+
+        match repo.check_ignore(&arg_key(arg), is_dir)? {
+            IgnoreStatus::Metadata => println!("{arg}: ignored (repository metadata)"),
+            IgnoreStatus::Reincluded { line, text } => {
+                println!("{arg}: not ignored (re-included by {IGNORE_FILE}:{line}: {text})");
+            }
+            IgnoreStatus::Ignored { line, text } => {
+                println!("{arg}: ignored by {IGNORE_FILE}:{line}: {text}");
+            }
+            IgnoreStatus::NotIgnored => println!("{arg}: not ignored"),
+        }
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: ADD
+// ---------------------------------------------------------
+
+fn add_cmd(repo: &Repository, paths: Vec<String>) -> Result<()> {
+    if paths.is_empty() {
+        return Err(ScmError::Usage("Usage: scm add <path>...".into()));
+    }
+
+    let keys: Vec<String> = paths.iter().map(|p| arg_key(p)).collect();
+    repo.add(&keys.iter().map(|k| k.as_str()).collect::<Vec<_>>())
+}
+
+// ---------------------------------------------------------
+// COMMAND: RM
+// ---------------------------------------------------------
+
+fn rm_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let options = RmOptions {
+        cached: args.iter().any(|a| a == "--cached"),
+        force: args.iter().any(|a| a == "--force"),
+    };
+    let paths: Vec<&String> = args.iter().filter(|a| !matches!(a.as_str(), "--cached" | "--force")).collect();
+
+    if paths.is_empty() {
+        return Err(ScmError::Usage("Usage: scm rm [--cached] [--force] <path>...".into()));
+    }
+
+    let keys: Vec<String> = paths.iter().map(|p| arg_key(p)).collect();
+    for k in repo.rm(&keys.iter().map(|k| k.as_str()).collect::<Vec<_>>(), options)? {
+        println!("rm {k}");
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: STATUS
+// ---------------------------------------------------------
+
+fn status_cmd(repo: &Repository) -> Result<()> {
+    let status = repo.status()?;
+
+    match repo.head_ref()? {
+        Head::Branch(name) => println!("On branch {name}"),
+        Head::Detached(id) => println!("HEAD detached at {}", id.short()),
+    }
+
+    if status.merging {
+        if status.conflicts.is_empty() {
+            println!("All conflicts fixed; run `scm commit` to conclude.");
+        } else {
+            println!("Unmerged paths:");
+            for path in &status.conflicts {
+                println!("  both modified:   {path}");
+            }
+        }
+    }
+
+    if status.is_clean() {
+        println!("Nothing to commit, working tree clean.");
+        return Ok(());
+    }
+
+    let describe = |change: &Change| match change {
+        Change::Added => "new file:  ",
+        Change::Modified => "modified:  ",
+        Change::Deleted => "deleted:   ",
+    };
+
+    if !status.staged.is_empty() {
+        println!("Changes to be committed:");
+        for (change, path) in &status.staged {
+            println!("  {} {path}", describe(change));
+        }
+    }
+    if !status.unstaged.is_empty() {
+        println!("Changes not staged for commit:");
+        for (change, path) in &status.unstaged {
+            println!("  {} {path}", describe(change));
+        }
+    }
+    if !status.untracked.is_empty() {
+        println!("Untracked files:");
+        for path in &status.untracked {
+            println!("  {path}");
+        }
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: FSCK
+// ---------------------------------------------------------
+//
+// Report on every commit reachable from a branch, tag or HEAD (see
+// check_repository). Exits non-zero if anything is wrong.
+
+fn fsck_cmd(repo: &Repository) -> Result<()> {
+    let report = repo.verify()?;
+
+    for (name, result) in &report.tags {
+        match result {
+            Ok(signer) => println!("ok      tag {name} (signed by {signer})"),
+            Err(problem) => println!("FAILED  tag {name}: {problem}"),
+        }
+    }
+    for (id, result) in &report.commits {
+        match result {
+            Ok(signer) => println!("ok      {id} (signed by {signer})"),
+            Err(problem) => println!("FAILED  {id}: {problem}"),
+        }
+    }
+    for id in &report.dangling {
+        println!("dangling {id}");
+    }
+
+    let bad = report.failures();
+    println!("{} commits checked, {bad} failed", report.commits.len());
+    if bad > 0 {
+        return Err(ScmError::Verification(format!("{bad} problems found")));
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: KEY
+// ---------------------------------------------------------
+
+fn key_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    match args.iter().map(|a| a.as_str()).collect::<Vec<_>>().as_slice() {
+        ["show"] => println!("{}", repo.public_key()?),
+        ["export", file] => {
+            // The exported file stays encrypted under the same passphrase
+            repo.export_key(user_path(file))?;
+            println!("Exported encrypted signing key to {file}");
+        }
+        ["import", file] | ["import", file, "--force"] => {
+            let public_key = repo.import_key(user_path(file), args.len() == 3)?;
+            println!("Imported signing key {}", short_id(&public_key));
+        }
+        ["list"] | [] => {
+            for k in repo.trusted_keys()? {
+                let status = if k.revoked { " (revoked)" } else { "" };
+                println!("{}  {}{status}", k.public_key, k.name);
+            }
+        }
+        ["add", name, public_key] => {
+            repo.trust_key(name, public_key)?;
+            println!("Trusted {name} ({})", short_id(public_key));
+        }
+        ["revoke", who] => {
+            for k in repo.revoke_key(who)? {
+                println!("Revoked {} ({})", k.name, short_id(&k.public_key));
+            }
+        }
+        _ => {
+            return Err(ScmError::Usage(
+                "Usage: scm key <show|list|add <name> <public-key>|revoke <name|public-key>>
+       scm key <export <file>|import <file> [--force]>"
+                    .into(),
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: BRANCH
+// ---------------------------------------------------------
+
+fn branch_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    match args.iter().map(|a| a.as_str()).collect::<Vec<_>>().as_slice() {
+        [] => {
+            let current = match repo.head_ref()? {
+                Head::Branch(name) => Some(name),
+                Head::Detached(id) => {
+                    println!("* (HEAD detached at {})", id.short());
+                    None
+                }
+            };
+
+            for name in repo.branches()? {
+                let marker = if current.as_deref() == Some(name.as_str()) { '*' } else { ' ' };
+                println!("{marker} {name}");
+            }
+        }
+        ["-d", name] => {
+            repo.delete_branch(name)?;
+            println!("Deleted branch {name}");
+        }
+        [name] | [name, _] => {
+            let target = repo.create_branch(name, args.get(1).map(|s| s.as_str()))?;
+            println!("Created branch {name} at {}", target.short());
+        }
+        _ => return Err(ScmError::Usage("Usage: scm branch [-d] [<name> [<commit>]]".into())),
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: CHECKOUT
+// ---------------------------------------------------------
+
+fn checkout_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let options = RestoreOptions {
+        keep: args.iter().any(|a| a == "--keep"),
+        force: args.iter().any(|a| a == "--force"),
+    };
+    let args: Vec<&String> = args.iter().filter(|a| *a != "--keep" && *a != "--force").collect();
+    let (spec, create) = match args.as_slice() {
+        [flag, name] if *flag == "-b" => (name.as_str(), true),
+        [spec] => (spec.as_str(), false),
+        _ => {
+            return Err(ScmError::Usage("Usage: scm checkout [--keep] [--force] [-b] <branch|commit>".into()));
+        }
+    };
+
+    if create {
+        repo.checkout_new_branch(spec)?;
+        println!("Switched to a new branch {spec}");
+        return Ok(());
+    }
+
+    match repo.checkout(spec, options)? {
+        Head::Branch(name) => println!("Switched to branch {name}"),
+        Head::Detached(id) => println!("HEAD is now detached at {}", id.short()),
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: MERGE
+// ---------------------------------------------------------
+
+fn merge_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    if args.iter().any(|a| a == "--abort") {
+        repo.abort_merge()?;
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let no_ff = args.iter().any(|a| a == "--no-ff");
+    let Some(spec) = args.iter().find(|a| !a.starts_with("--")) else {
+        return Err(ScmError::Usage("Usage: scm merge [--no-ff] <branch|commit> | --abort".into()));
+    };
+
+    match repo.merge(spec, no_ff)? {
+        MergeOutcome::UpToDate => println!("Already up to date."),
+        MergeOutcome::FastForward(id) => println!("Fast-forward to {}", id.short()),
+        MergeOutcome::Merged(id) => println!("Merged {spec} as {}", id.short()),
+        MergeOutcome::Conflicts(conflicts) => {
+            for conflict in &conflicts {
+                println!("{conflict}");
+            }
+            return Err(ScmError::Conflict("Automatic merge failed; fix conflicts, `scm add` them, then `scm commit`.".into()));
+        }
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: TAG
+// ---------------------------------------------------------
+
+fn tag_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let usage = || {
+        ScmError::Usage(
+            "Usage: scm tag [<name> [<commit>]]
+       scm tag -a <name> -m <message> [<commit>]
+       scm tag -d <name> | --verify <name>"
+                .into(),
+        )
+    };
+
+    let mut annotate = false;
+    let mut message = None;
+    let mut rest = Vec::new();
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-a" => annotate = true,
+            "-m" => match it.next() {
+                Some(m) => {
+                    annotate = true;
+                    message = Some(m);
+                }
+                None => return Err(usage()),
+            },
+            _ => rest.push(arg),
+        }
+    }
+
+    match rest.iter().map(|a| a.as_str()).collect::<Vec<_>>().as_slice() {
+        [] if !annotate => {
+            for tag in repo.tags()? {
+                match tag.message {
+                    Some(message) => {
+                        println!("{:<20} {} {}", tag.name, tag.target.short(), message.lines().next().unwrap_or(""));
+                    }
+                    None => println!("{:<20} {}", tag.name, tag.target.short()),
+                }
+            }
+        }
+        ["-d", name] => {
+            repo.delete_tag(name)?;
+            println!("Deleted tag {name}");
+        }
+        ["--verify", name] => {
+            let (signer, target) = repo.verify_tag(name)?;
+            println!("Good signature on tag {name} from {signer}");
+            println!("Target commit {} verified", target.short());
+        }
+        [name] | [name, _] => {
+            if annotate && message.is_none() {
+                return Err(ScmError::Usage("Annotated tags need a message (-m)".into()));
+            }
+            let rev = rest.get(1).map(|s| s.as_str()).unwrap_or("HEAD");
+            let target = repo.create_tag(name, rev, message.as_deref())?;
+            println!("Tagged {} as {name}", target.short());
+        }
+        _ => return Err(usage()),
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: PROVE
+// ---------------------------------------------------------
+
+fn prove_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let [rev, path] = args.as_slice() else {
+        return Err(ScmError::Usage("Usage: scm prove <commit> <path>".into()));
+    };
+
+    let proof = repo.prove(rev, &arg_key(path))?;
+    println!("{}", serde_json::to_string_pretty(&proof).unwrap());
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: VERIFY-PROOF
+// ---------------------------------------------------------
+//
+// Inside a repository its trusted keys decide; elsewhere only the local
+// user's own key is trusted.
+
+fn verify_proof_cmd(repo: Option<&Repository>, args: Vec<String>) -> Result<()> {
+    let [proof_file, file] = args.as_slice() else {
+        return Err(ScmError::Usage("Usage: scm verify-proof <proof.json> <file>".into()));
+    };
+
+    let proof: InclusionProof = serde_json::from_str(&fs::read_to_string(user_path(proof_file))?)
+        .map_err(ScmError::from)?;
+    let data = fs::read(user_path(file))?;
+
+    let result = match repo {
+        Some(repo) => repo.verify_proof(&proof, &data),
+        None => verify_proof(&proof, &data),
+    };
+    match result {
+        Ok(signer) => {
+            println!("Proof OK: {} is in commit {} (signed by {signer})", proof.path(), short_id(proof.commit()));
+            Ok(())
+        }
+        Err(ScmError::Verification(problem)) => Err(ScmError::Verification(format!("Proof FAILED: {problem}"))),
+        Err(e) => Err(e),
+    }
+}
+
+// ---------------------------------------------------------
+// COMMAND: CLONE
+// ---------------------------------------------------------
+//
+// The clone starts out trusting the keys the source trusts, plus the
+// local user's own key.
+
+fn clone_cmd(args: Vec<String>) -> Result<()> {
+    let (src, dest) = match args.as_slice() {
+        [src] => (src.clone(), None),
+        [src, dest] => (src.clone(), Some(dest.clone())),
+        _ => {
+            return Err(ScmError::Usage("Usage: scm clone <path> [<directory>]".into()));
+        }
+    };
+
+    let dest = match dest {
+        Some(dest) => user_path(&dest),
+        None => {
+            let remote = remote_scm_dir(&user_path(&src))?;
+            user_path(&remote.parent().and_then(|p| p.file_name()).unwrap_or_default().to_string_lossy())
+        }
+    };
+
+    let repo = Repository::clone_from(user_path(&src), dest, Identity::default())?;
+    for notice in repo.take_notices() {
+        eprintln!("{notice}");
+    }
+    println!("Cloned {src} into {}", fs::canonicalize(repo.root())?.display());
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: PUSH
+// ---------------------------------------------------------
+
+fn push_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let force = args.iter().any(|a| a == "--force");
+    let Some(path) = args.iter().find(|a| !a.starts_with("--")) else {
+        return Err(ScmError::Usage("Usage: scm push [--force] <path>".into()));
+    };
+
+    match repo.push(user_path(path), force)? {
+        TransferOutcome::UpToDate => println!("Everything up to date."),
+        TransferOutcome::Updated { branch, tip, received } => {
+            println!("Pushed {branch} to {} ({received} new commits)", tip.short());
+        }
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: PULL
+// ---------------------------------------------------------
+
+fn pull_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let force = args.iter().any(|a| a == "--force");
+    let Some(path) = args.iter().find(|a| !a.starts_with("--")) else {
+        return Err(ScmError::Usage("Usage: scm pull [--force] <path>".into()));
+    };
+
+    match repo.pull(user_path(path), force)? {
+        TransferOutcome::UpToDate => println!("Already up to date."),
+        TransferOutcome::Updated { branch, tip, received } => {
+            println!("Updated {branch} to {} ({received} new commits)", tip.short());
+        }
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: BUNDLE
+// ---------------------------------------------------------
+
+fn bundle_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("create") if args.len() == 2 || args.len() == 3 => {
+            let file = &args[1];
+            let (commits, objects) = repo.create_bundle(user_path(file), args.get(2).map_or("HEAD", String::as_str))?;
+            println!("Wrote {commits} commits and {objects} objects to {file}");
+            Ok(())
+        }
+        Some("unbundle") if args.len() == 2 => {
+            let imported = repo.unbundle(user_path(&args[1]))?;
+            println!("Imported {} commits", imported.received);
+            for (name, id) in &imported.refs {
+                let new = if imported.created.contains(name) { " (new branch)" } else { "" };
+                println!("{name:<20} {id}{new}");
+            }
+            Ok(())
+        }
+        _ => Err(ScmError::Usage(
+            "Usage: scm bundle create <file> [<rev> | <from>..<to>]
+       scm bundle unbundle <file>"
+                .into(),
+        )),
+    }
+}
+
+// ---------------------------------------------------------
+// COMMAND: GC
+// ---------------------------------------------------------
+//
+// Rewrite every object compressed. With --delta, each version of a file
+// is stored as a delta against the next newer version of the same path
+// when that is smaller. Newer versions are decided first, so a delta's
+// base has always been rewritten already in this run and chains cannot
+// loop back.
+
+fn gc_cmd(repo: &Repository, args: Vec<String>) -> Result<()> {
+    let use_delta = match args.as_slice() {
+        [] => false,
+        [flag] if flag == "--delta" => true,
+        _ => {
+            return Err(ScmError::Usage("Usage: scm gc [--delta]".into()));
+        }
+    };
+
+    let GcReport { rewritten, deltas, before, after } = repo.gc(use_delta)?;
+    let saved = before.saturating_sub(after);
+    println!("Repacked {rewritten} objects ({deltas} as deltas): {before} -> {after} bytes");
+    println!(
+        "Saved {saved} bytes ({:.1}%)",
+        if before == 0 { 0.0 } else { saved as f64 * 100.0 / before as f64 }
+    );
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// COMMAND: INIT
+// ---------------------------------------------------------
+
+fn init_cmd(args: Vec<String>) -> Result<()> {
+    let dir = match args.as_slice() {
+        [] => user_path("."),
+        [dir] => user_path(dir),
+        _ => {
+            return Err(ScmError::Usage("Usage: scm init [<directory>]".into()));
+        }
+    };
+
+    let existed = dir.join(".scm").is_dir();
+    let repo = Repository::init(&dir, Identity::default())?;
+    let scm_dir = fs::canonicalize(repo.root().join(".scm"))?;
+
+    if existed {
+        println!("Already an scm repository: {}", scm_dir.display());
+    } else {
+        println!("Initialized empty scm repository in {}", scm_dir.display());
+    }
+    Ok(())
+}
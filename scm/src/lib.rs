@@ -0,0 +1,5491 @@
+//! A version control system whose commits are signed and whose snapshots
+//! are content-addressed Merkle trees.
+//!
+//! [`Repository`] is the entry point for programs. The `scm` binary is a
+//! thin wrapper around [`cli::run`].
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, SigningKey, Verifier, VerifyingKey};
+use ed25519_dalek::Signer; // required for sign()
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub mod cli;
+
+// ---------------------------------------------------------
+// ERRORS
+// ---------------------------------------------------------
+//
+// Each class of failure exits with its own status so scripts can tell a
+// mistyped argument from a tampered repository:
+//
+//   1 I/O   2 usage   3 not a repository   4 not found
+//   5 corrupt data   6 verification failed   7 refused   8 conflicts
+
+#[derive(Debug)]
+pub enum ScmError {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// The command line was wrong.
+    Usage(String),
+    /// No .scm directory here or above.
+    NotARepo,
+    /// A commit, branch, tag, path or key that does not exist.
+    NotFound(String),
+    /// Repository data is missing, malformed or inconsistent.
+    Corrupt(String),
+    /// A hash, Merkle root or signature did not check out, or the signer
+    /// is not trusted.
+    Verification(String),
+    /// The repository is not in a state that allows the command.
+    Refused(String),
+    /// A merge or revert stopped with conflicts for the user to resolve.
+    Conflict(String),
+}
+
+pub type Result<T, E = ScmError> = std::result::Result<T, E>;
+
+impl ScmError {
+    /// Status the scm command exits with for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ScmError::Io(_) => 1,
+            ScmError::Usage(_) => 2,
+            ScmError::NotARepo => 3,
+            ScmError::NotFound(_) => 4,
+            ScmError::Corrupt(_) => 5,
+            ScmError::Verification(_) => 6,
+            ScmError::Refused(_) => 7,
+            ScmError::Conflict(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for ScmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScmError::Io(e) => write!(f, "{e}"),
+            ScmError::NotARepo => {
+                write!(f, "not an scm repository (or any parent directory); run `scm init` to create one")
+            }
+            ScmError::Usage(msg)
+            | ScmError::NotFound(msg)
+            | ScmError::Corrupt(msg)
+            | ScmError::Verification(msg)
+            | ScmError::Refused(msg)
+            | ScmError::Conflict(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ScmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScmError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ScmError {
+    fn from(e: io::Error) -> Self {
+        ScmError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ScmError {
+    fn from(e: serde_json::Error) -> Self {
+        ScmError::Corrupt(e.to_string())
+    }
+}
+
+// ---------------------------------------------------------
+// NOTICES
+// ---------------------------------------------------------
+//
+// Some things worth telling the user are not part of what an operation
+// returns, such as a signing key being generated on first use. The engine
+// records them for the thread and Repository hands them to its caller.
+
+/// Something that happened on the side of an operation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Notice {
+    /// An interrupted command left this many incomplete entries behind,
+    /// and they were removed.
+    Recovered(usize),
+    /// A new signing key was generated and saved here.
+    KeyGenerated(PathBuf),
+    /// A plaintext key left by an older version was encrypted and moved
+    /// here.
+    KeyMoved(PathBuf),
+    /// A plaintext key left by an older version is being ignored.
+    PlaintextKeyIgnored(PathBuf),
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notice::Recovered(n) => write!(f, "Cleaned up {n} incomplete entries left by an interrupted scm command."),
+            Notice::KeyGenerated(path) => write!(f, "Generated signing key in {}", path.display()),
+            Notice::KeyMoved(path) => write!(f, "Moved signing key to {} (now encrypted)", path.display()),
+            Notice::PlaintextKeyIgnored(path) => write!(
+                f,
+                "warning: ignoring plaintext key {}; import it with `scm key import` or delete it",
+                path.display()
+            ),
+        }
+    }
+}
+
+thread_local! {
+    static NOTICES: RefCell<Vec<Notice>> = const { RefCell::new(Vec::new()) };
+}
+
+fn notify(notice: Notice) {
+    NOTICES.with(|n| n.borrow_mut().push(notice));
+}
+
+/// Run `f`, returning its result along with the notices it recorded.
+fn collect_notices<T>(f: impl FnOnce() -> T) -> (T, Vec<Notice>) {
+    let outer = NOTICES.with(|n| n.take());
+    let out = f();
+    (out, NOTICES.with(|n| n.replace(outer)))
+}
+
+// ---------------------------------------------------------
+// PATH HELPERS
+// ---------------------------------------------------------
+//
+// Repository paths are relative to the root of the repository the
+// current thread works on, which Repository sets for the duration of each
+// call. The process's working directory is never changed, so threads can
+// work on different repositories at once. Paths given on the command line
+// are relative to where scm was invoked.
+
+/// The repository a thread works on and who is acting in it.
+#[derive(Clone)]
+struct Context {
+    root: PathBuf,
+    identity: Identity,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context {
+        root: PathBuf::from("."),
+        identity: Identity::default(),
+    });
+}
+
+/// Puts the previous context back when dropped.
+struct ContextGuard(Option<Context>);
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            CONTEXT.with(|c| *c.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Run `f` against the repository at `root`, acting as `identity`.
+fn with_context<T>(root: &Path, identity: &Identity, f: impl FnOnce() -> T) -> T {
+    let context = Context { root: root.to_path_buf(), identity: identity.clone() };
+    let _restore = ContextGuard(Some(CONTEXT.with(|c| c.replace(context))));
+    f()
+}
+
+/// Run `f` against another repository as the same identity.
+fn with_root<T>(root: &Path, f: impl FnOnce() -> T) -> T {
+    with_context(root, &identity(), f)
+}
+
+fn repo_root() -> PathBuf {
+    CONTEXT.with(|c| c.borrow().root.clone())
+}
+
+fn identity() -> Identity {
+    CONTEXT.with(|c| c.borrow().identity.clone())
+}
+
+/// Nearest directory at or above `start` that holds a .scm directory.
+fn find_repo_root(start: &Path) -> Option<PathBuf> {
+    start.ancestors().find(|dir| dir.join(".scm").is_dir()).map(Path::to_path_buf)
+}
+
+/// A command-line path as a filesystem path.
+fn user_path(arg: &str) -> PathBuf {
+    env::current_dir().unwrap_or_default().join(arg)
+}
+
+/// Where the file with repository key `key` lives in the working tree.
+fn work_path(key: impl AsRef<Path>) -> PathBuf {
+    repo_root().join(key)
+}
+
+/// Resolve "." and ".." without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn scm_dir() -> PathBuf {
+    repo_root().join(".scm")
+}
+
+/// Linear list of commit IDs written by older versions. Still consulted
+/// for the parent of commits that predate parent pointers.
+fn legacy_log_path() -> PathBuf {
+    scm_dir().join("log")
+}
+
+fn head_path() -> PathBuf {
+    scm_dir().join("HEAD")
+}
+
+fn heads_dir() -> PathBuf {
+    scm_dir().join("refs").join("heads")
+}
+
+fn tags_dir() -> PathBuf {
+    scm_dir().join("refs").join("tags")
+}
+
+/// Second parent of a merge with conflicts waiting to be committed.
+fn merge_head_path() -> PathBuf {
+    scm_dir().join("MERGE_HEAD")
+}
+
+/// Present while a merge or revert with conflicts is waiting to be
+/// committed; holds the default commit message.
+fn merge_msg_path() -> PathBuf {
+    scm_dir().join("MERGE_MSG")
+}
+
+fn merge_conflicts_path() -> PathBuf {
+    scm_dir().join("MERGE_CONFLICTS")
+}
+
+/// Held for the duration of every command; see open_repo.
+fn lock_path() -> PathBuf {
+    scm_dir().join("lock")
+}
+
+/// Files are written here first and renamed into place.
+fn tmp_dir() -> PathBuf {
+    scm_dir().join("tmp")
+}
+
+fn commits_dir() -> PathBuf {
+    scm_dir().join("commits")
+}
+
+fn config_path() -> PathBuf {
+    scm_dir().join("config")
+}
+
+fn index_path() -> PathBuf {
+    scm_dir().join("index")
+}
+
+fn objects_dir() -> PathBuf {
+    scm_dir().join("objects")
+}
+
+/// Plaintext key written inside the repository by older versions.
+fn legacy_key_path() -> PathBuf {
+    scm_dir().join("keys").join("ed25519_sk")
+}
+
+/// Per-user directory for the signing key: $SCM_HOME, else
+/// $XDG_CONFIG_HOME/scm, else ~/.config/scm.
+fn user_config_dir() -> PathBuf {
+    if let Some(dir) = identity().key_dir {
+        return dir;
+    }
+    if let Some(dir) = env::var_os("SCM_HOME") {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        return PathBuf::from(dir).join("scm");
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).unwrap_or_default();
+    PathBuf::from(home).join(".config").join("scm")
+}
+
+fn signing_key_path() -> PathBuf {
+    user_config_dir().join("signing_key")
+}
+
+fn public_key_path() -> PathBuf {
+    user_config_dir().join("signing_key.pub")
+}
+
+fn trusted_keys_path() -> PathBuf {
+    scm_dir().join("trusted_keys")
+}
+
+// ---------------------------------------------------------
+// METADATA STRUCT
+// ---------------------------------------------------------
+
+/// Commits written before tree objects existed: `root` is the Merkle root
+/// of the top-level file hashes.
+const META_VERSION_FLAT: u32 = 0;
+/// `root` is the hash of the root tree object.
+const META_VERSION_TREE: u32 = 1;
+/// The signature also covers parent, author, timestamp and message.
+const META_VERSION_HEADER: u32 = 2;
+/// The signature covers the SHA-256 of the canonical commit serialization,
+/// and that hash is the commit ID.
+const META_VERSION_CANONICAL: u32 = 3;
+/// Tree hashes prefix leaves with 0x00 and interior nodes with 0x01, and
+/// promote an odd node instead of pairing it with itself.
+const META_VERSION_MERKLE: u32 = 4;
+/// Files may be executable or symlinks; their modes are in file_modes and
+/// in the tree entries.
+const META_VERSION_MODES: u32 = 5;
+
+/// A commit as stored in .scm/commits/<id>/meta.json.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommitMeta {
+    /// Format version, from 0 (flat Merkle root) to 5 (file modes).
+    #[serde(default)]
+    pub version: u32,
+    /// Hash of the root tree (Merkle root of the files for version 0).
+    pub root: String,
+    #[serde(default, alias = "parent", deserialize_with = "one_or_many")]
+    pub parents: Vec<String>,
+    #[serde(default)]
+    pub author: String,
+    /// Seconds since the Unix epoch (UTC).
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub message: String,
+    /// Path (relative to the repository, `/`-separated) -> blob hash.
+    pub file_hashes: BTreeMap<String, String>,
+    /// Mode of each file that is not a regular one: "100755" for an
+    /// executable, "120000" for a symlink whose blob is the link target.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub file_modes: BTreeMap<String, String>,
+    /// Hex Ed25519 verifying key of the signer. Empty for commits made
+    /// before keys were recorded; those are checked against the local key.
+    #[serde(default)]
+    pub public_key: String,
+    pub signature: String,
+}
+
+/// A file in a snapshot: its blob hash and its mode (MODE_FILE, MODE_EXEC
+/// or MODE_LINK).
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    hash: String,
+    mode: String,
+}
+
+/// Path -> entry for every file of a commit, the index or the working tree.
+type Snapshot = BTreeMap<String, Entry>;
+
+impl CommitMeta {
+    fn snapshot(&self) -> Snapshot {
+        join_snapshot(&self.file_hashes, &self.file_modes)
+    }
+}
+
+fn join_snapshot(hashes: &BTreeMap<String, String>, modes: &BTreeMap<String, String>) -> Snapshot {
+    hashes
+        .iter()
+        .map(|(path, hash)| {
+            let mode = modes.get(path).map_or(MODE_FILE, String::as_str);
+            (path.clone(), Entry { hash: hash.clone(), mode: mode.into() })
+        })
+        .collect()
+}
+
+/// The path -> hash and path -> mode maps stored for a snapshot. Regular
+/// files are left out of the modes.
+fn split_snapshot(snapshot: &Snapshot) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    let hashes = snapshot.iter().map(|(path, e)| (path.clone(), e.hash.clone())).collect();
+    let modes = snapshot
+        .iter()
+        .filter(|(_, e)| e.mode != MODE_FILE)
+        .map(|(path, e)| (path.clone(), e.mode.clone()))
+        .collect();
+    (hashes, modes)
+}
+
+/// Accept the old single `parent` (string or null) as well as `parents`.
+fn one_or_many<'de, D>(d: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Option<String>),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(p) => p.into_iter().collect(),
+        OneOrMany::Many(ps) => ps,
+    })
+}
+
+// ---------------------------------------------------------
+// HEX ENCODE / DECODE
+// ---------------------------------------------------------
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(ScmError::Corrupt("Invalid hex length".into()));
+    }
+
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| ScmError::Corrupt("Invalid hex".into()))
+        })
+        .collect()
+}
+
+// ---------------------------------------------------------
+// INIT REPO + KEY
+// ---------------------------------------------------------
+
+fn ensure_repo() -> Result<()> {
+    if !scm_dir().exists() { fs::create_dir(scm_dir())?; }
+    if !commits_dir().exists() { fs::create_dir(commits_dir())?; }
+    if !objects_dir().exists() { fs::create_dir(objects_dir())?; }
+    if !heads_dir().exists() { fs::create_dir_all(heads_dir())?; }
+    if !head_path().exists() { write_atomic(&head_path(), format!("ref: refs/heads/{DEFAULT_BRANCH}\n"))?; }
+
+    if let Some(public_key) = local_public_key()? {
+        trust_own_key(&public_key)?;
+    }
+
+    Ok(())
+}
+
+/// A new repository trusts its owner's key.
+fn trust_own_key(public_key: &str) -> Result<()> {
+    if scm_dir().exists() && !trusted_keys_path().exists() {
+        save_trusted_keys(&[TrustedKey {
+            name: author_identity()?,
+            public_key: public_key.to_string(),
+            revoked: false,
+        }])?;
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// LOCKING + ATOMIC WRITES
+// ---------------------------------------------------------
+//
+// One scm process works on a repository at a time: .scm/lock is created
+// exclusively when a command starts and removed when it ends. Files under
+// .scm are written to .scm/tmp and renamed into place, and a new commit's
+// directory is assembled there too, so a crash leaves either the old state
+// or the new one. Anything still in .scm/tmp when the next command starts
+// was left by an interrupted run and is thrown away. The lock holds its
+// process's ID; on Linux, where /proc tells whether that process is still
+// running, a lock left by a crash is taken over. Elsewhere it has to be
+// removed by hand.
+
+struct RepoLock(PathBuf);
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn lock_repo() -> Result<RepoLock> {
+    // Absolute, so the message below names the lock file in full
+    let path = std::path::absolute(lock_path())?;
+
+    if let Some(pid) = fs::read_to_string(&path).ok().filter(|pid| !lock_holder_alive(pid.trim())) {
+        // Move the lock aside before removing it, so that of several
+        // processes finding it stale only one removes it, and a live lock
+        // taken meanwhile is put back
+        let aside = tmp_path()?;
+        if fs::rename(&path, &aside).is_ok() {
+            if fs::read_to_string(&aside).is_ok_and(|now| now == pid) {
+                fs::remove_file(&aside)?;
+            } else {
+                fs::rename(&aside, &path)?;
+            }
+        }
+    }
+
+    match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut file) => {
+            writeln!(file, "{}", std::process::id())?;
+            Ok(RepoLock(path))
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let pid = fs::read_to_string(&path).unwrap_or_default();
+            Err(ScmError::Refused(format!(
+                "Repository is locked by another scm process (pid {}). If none is running, remove {}.",
+                pid.trim(),
+                path.display()
+            )))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Could the process that wrote a lock holding `pid` still be running?
+/// Assumed so unless /proc says otherwise.
+fn lock_holder_alive(pid: &str) -> bool {
+    match pid.parse::<u32>() {
+        Ok(pid) if cfg!(target_os = "linux") => {
+            pid == std::process::id() || Path::new("/proc").join(pid.to_string()).exists()
+        }
+        _ => true,
+    }
+}
+
+/// Lock the context's repository and bring it to a
+/// consistent, current-format state.
+fn open_repo() -> Result<RepoLock> {
+    let lock = lock_repo()?;
+    recover_interrupted()?;
+    migrate_legacy_commits()?;
+    migrate_log_to_refs()?;
+    Ok(lock)
+}
+
+/// Drop whatever an interrupted command left behind: staged files and
+/// commit directories that never received their metadata.
+fn recover_interrupted() -> Result<()> {
+    let mut cleaned = 0;
+
+    if tmp_dir().exists() {
+        cleaned += fs::read_dir(tmp_dir())?.count();
+        fs::remove_dir_all(tmp_dir())?;
+    }
+
+    if commits_dir().exists() {
+        for entry in fs::read_dir(commits_dir())? {
+            let path = entry?.path();
+            if path.is_dir() && !path.join("meta.json").exists() {
+                fs::remove_dir_all(&path)?;
+                cleaned += 1;
+            }
+        }
+    }
+
+    if cleaned > 0 {
+        notify(Notice::Recovered(cleaned));
+    }
+    Ok(())
+}
+
+/// A fresh path in .scm/tmp.
+fn tmp_path() -> Result<PathBuf> {
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    fs::create_dir_all(tmp_dir())?;
+    let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    Ok(tmp_dir().join(format!("{}-{n}", std::process::id())))
+}
+
+/// Replace `path` with `data` in one step.
+fn write_atomic(path: &Path, data: impl AsRef<[u8]>) -> Result<()> {
+    let tmp = tmp_path()?;
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data.as_ref())?;
+    file.sync_all()?;
+    Ok(fs::rename(tmp, path)?)
+}
+
+/// Write a commit's metadata; the commit appears all at once or not at all.
+fn store_commit(id: &str, raw: &str) -> Result<()> {
+    let dest = commits_dir().join(id);
+    if dest.join("meta.json").exists() {
+        return Ok(());
+    }
+
+    let staging = tmp_path()?;
+    fs::create_dir(&staging)?;
+    write_atomic(&staging.join("meta.json"), raw)?;
+    fs::create_dir_all(commits_dir())?;
+    Ok(fs::rename(staging, dest)?)
+}
+
+// ---------------------------------------------------------
+// SIGNING KEY STORAGE
+// ---------------------------------------------------------
+//
+// The Ed25519 secret lives outside the working tree (see user_config_dir)
+// and is encrypted with ChaCha20-Poly1305 under a key derived from a
+// passphrase with Argon2id. The public half is kept next to it in plain
+// hex so verification never needs the passphrase.
+//
+// The passphrase comes from SCM_PASSPHRASE, then the file named by
+// SCM_PASSPHRASE_FILE, then an interactive prompt.
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedKey {
+    kdf: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+    public_key: String,
+}
+
+fn read_passphrase(confirm: bool) -> Result<String> {
+    if let Some(p) = identity().passphrase {
+        return Ok(p);
+    }
+    if let Ok(p) = env::var("SCM_PASSPHRASE") {
+        return Ok(p);
+    }
+    if let Some(file) = env::var_os("SCM_PASSPHRASE_FILE") {
+        let text = fs::read_to_string(file)?;
+        return Ok(text.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let pass = rpassword::prompt_password("Passphrase for signing key: ")?;
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != pass {
+        return Err(ScmError::Usage("Passphrases do not match".into()));
+    }
+    Ok(pass)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], m: u32, t: u32, p: u32) -> Result<[u8; 32]> {
+    let params = Params::new(m, t, p, Some(32))
+        .map_err(|e| ScmError::Corrupt(e.to_string()))?;
+    let mut out = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut out)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(out)
+}
+
+fn encrypt_key(sk: &SigningKey, passphrase: &str) -> Result<EncryptedKey> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let (m, t, p) = (Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST);
+    let key = derive_key(passphrase, &salt, m, t, p)?;
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .encrypt(Nonce::from_slice(&nonce), sk.to_bytes().as_slice())
+        .map_err(|_| io::Error::other("Encryption failed"))?;
+
+    Ok(EncryptedKey {
+        kdf: "argon2id".into(),
+        m_cost: m,
+        t_cost: t,
+        p_cost: p,
+        salt: to_hex(&salt),
+        nonce: to_hex(&nonce),
+        ciphertext: to_hex(&ciphertext),
+        public_key: to_hex(sk.verifying_key().as_bytes()),
+    })
+}
+
+fn decrypt_key(enc: &EncryptedKey, passphrase: &str) -> Result<SigningKey> {
+    if enc.kdf != "argon2id" {
+        return Err(ScmError::Corrupt(format!("Unsupported KDF {}", enc.kdf)));
+    }
+
+    let key = derive_key(passphrase, &from_hex(&enc.salt)?, enc.m_cost, enc.t_cost, enc.p_cost)?;
+    let nonce = from_hex(&enc.nonce)?;
+    if nonce.len() != 12 {
+        return Err(ScmError::Corrupt("Bad nonce length".into()));
+    }
+
+    let plain = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt(Nonce::from_slice(&nonce), from_hex(&enc.ciphertext)?.as_slice())
+        .map_err(|_| ScmError::Verification("Wrong passphrase or corrupt key file".into()))?;
+
+    let secret = <[u8; 32]>::try_from(plain.as_slice())
+        .map_err(|_| ScmError::Corrupt("Signing key must be 32 bytes".into()))?;
+    let sk = SigningKey::from_bytes(&secret);
+
+    if to_hex(sk.verifying_key().as_bytes()) != enc.public_key {
+        return Err(ScmError::Corrupt("Key file public key mismatch".into()));
+    }
+    Ok(sk)
+}
+
+fn parse_raw_key(hex: &str) -> Result<SigningKey> {
+    let bytes = from_hex(hex.trim())?;
+    let secret = <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| ScmError::Corrupt("Signing key must be 32 bytes".into()))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+fn save_signing_key(enc: &EncryptedKey) -> Result<()> {
+    let mut dir = fs::DirBuilder::new();
+    dir.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        dir.mode(0o700);
+    }
+    dir.create(user_config_dir())?;
+
+    write_private(&signing_key_path(), serde_json::to_string_pretty(enc).unwrap().as_bytes())?;
+    fs::write(public_key_path(), format!("{}\n", enc.public_key))?;
+    trust_own_key(&enc.public_key)
+}
+
+/// Replace `path` with a file only its owner can read. The mode is set as
+/// the file is created, so the contents are never readable by others, even
+/// briefly.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    if tmp.exists() {
+        fs::remove_file(&tmp)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(fs::rename(tmp, path)?)
+}
+
+fn load_encrypted_key(path: &Path) -> Result<EncryptedKey> {
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(ScmError::from)
+}
+
+/// Hex public key of this user's signing key, if one exists.
+fn local_public_key() -> Result<Option<String>> {
+    if public_key_path().exists() {
+        return Ok(Some(fs::read_to_string(public_key_path())?.trim().to_string()));
+    }
+    if legacy_key_path().exists() {
+        let sk = parse_raw_key(&fs::read_to_string(legacy_key_path())?)?;
+        return Ok(Some(to_hex(sk.verifying_key().as_bytes())));
+    }
+    Ok(None)
+}
+
+fn load_or_generate_signing_key() -> Result<SigningKey> {
+    let path = signing_key_path();
+
+    if path.exists() {
+        if legacy_key_path().exists() {
+            notify(Notice::PlaintextKeyIgnored(legacy_key_path()));
+        }
+        return decrypt_key(&load_encrypted_key(&path)?, &read_passphrase(false)?);
+    }
+
+    // Move a plaintext key left in the repository by older versions
+    if legacy_key_path().exists() {
+        let sk = parse_raw_key(&fs::read_to_string(legacy_key_path())?)?;
+        save_signing_key(&encrypt_key(&sk, &read_passphrase(true)?)?)?;
+        fs::remove_file(legacy_key_path())?;
+        let _ = fs::remove_dir(legacy_key_path().parent().unwrap());
+        notify(Notice::KeyMoved(path));
+        return Ok(sk);
+    }
+
+    // Generate new 32-byte random key
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+
+    let sk = SigningKey::from_bytes(&secret);
+
+    save_signing_key(&encrypt_key(&sk, &read_passphrase(true)?)?)?;
+    notify(Notice::KeyGenerated(path));
+
+    Ok(sk)
+}
+
+/// Copy the signing key, still encrypted under its passphrase, to `file`.
+fn export_signing_key(file: &Path) -> Result<()> {
+    if !signing_key_path().exists() {
+        return Err(ScmError::NotFound("No signing key to export.".into()));
+    }
+    fs::copy(signing_key_path(), file)?;
+    Ok(())
+}
+
+/// Install the signing key in `file`: an exported (encrypted) key, which
+/// the passphrase must open, or a raw hex secret, which gets encrypted.
+/// Returns its public key.
+fn import_signing_key(file: &Path, force: bool) -> Result<String> {
+    if signing_key_path().exists() && !force {
+        return Err(ScmError::Refused(format!("A signing key already exists at {}; use --force to replace it", signing_key_path().display())));
+    }
+    let text = fs::read_to_string(file)?;
+    let enc = match serde_json::from_str::<EncryptedKey>(&text) {
+        Ok(enc) => {
+            // Make sure the passphrase opens it before installing
+            decrypt_key(&enc, &read_passphrase(false)?)?;
+            enc
+        }
+        Err(_) => encrypt_key(&parse_raw_key(&text)?, &read_passphrase(true)?)?,
+    };
+    save_signing_key(&enc)?;
+    Ok(enc.public_key)
+}
+
+// ---------------------------------------------------------
+// TRUSTED KEYS
+// ---------------------------------------------------------
+//
+// .scm/trusted_keys lists the verifying keys whose signatures are accepted,
+// each with a name for reporting. Revoked keys stay listed so commits they
+// signed are reported as such rather than as unknown.
+
+/// A key whose signatures are accepted, and the name they are reported
+/// under.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrustedKey {
+    pub name: String,
+    /// Hex Ed25519 verifying key.
+    pub public_key: String,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+fn load_trusted_keys() -> Result<Vec<TrustedKey>> {
+    // Repositories from before the trust list implicitly trust the local key
+    if !trusted_keys_path().exists() {
+        return local_trust();
+    }
+    serde_json::from_str(&fs::read_to_string(trusted_keys_path())?)
+        .map_err(ScmError::from)
+}
+
+/// A trust list holding just the local user's key, if there is one.
+fn local_trust() -> Result<Vec<TrustedKey>> {
+    Ok(local_public_key()?
+        .map(|public_key| TrustedKey {
+            name: "local key".into(),
+            public_key,
+            revoked: false,
+        })
+        .into_iter()
+        .collect())
+}
+
+fn save_trusted_keys(keys: &[TrustedKey]) -> Result<()> {
+    write_atomic(&trusted_keys_path(), serde_json::to_string_pretty(keys).unwrap())
+}
+
+fn parse_verifying_key(hex: &str) -> Result<VerifyingKey> {
+    let bytes = from_hex(hex)?;
+    let arr = <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| ScmError::Corrupt("Public key must be 32 bytes".into()))?;
+    VerifyingKey::from_bytes(&arr)
+        .map_err(|_| ScmError::Corrupt("Invalid Ed25519 public key".into()))
+}
+
+/// Name of the trusted identity holding `public_key`, or why it is not trusted.
+fn trusted_signer(keys: &[TrustedKey], public_key: &str) -> Result<String, String> {
+    match keys.iter().find(|k| k.public_key == public_key) {
+        Some(k) if k.revoked => Err(format!("Signed by revoked key {} ({})", k.name, short_id(public_key))),
+        Some(k) => Ok(k.name.clone()),
+        None => Err(format!("Signed by unknown key {}", short_id(public_key))),
+    }
+}
+
+/// Trust `public_key` under `name`, renaming and reinstating it if it is
+/// already listed.
+fn trust_key(name: &str, public_key: &str) -> Result<()> {
+    if parse_verifying_key(public_key).is_err() {
+        return Err(ScmError::Usage(format!("Not a valid Ed25519 public key: {public_key}")));
+    }
+
+    let mut keys = load_trusted_keys()?;
+    match keys.iter_mut().find(|k| k.public_key == public_key) {
+        Some(k) => {
+            k.name = name.to_string();
+            k.revoked = false;
+        }
+        None => keys.push(TrustedKey {
+            name: name.to_string(),
+            public_key: public_key.to_string(),
+            revoked: false,
+        }),
+    }
+    save_trusted_keys(&keys)
+}
+
+/// Revoke every trusted key named `who` or with that public key. Returns
+/// the keys revoked.
+fn revoke_key(who: &str) -> Result<Vec<TrustedKey>> {
+    let mut keys = load_trusted_keys()?;
+    let mut revoked = Vec::new();
+    for k in keys.iter_mut().filter(|k| k.name == who || k.public_key == who) {
+        k.revoked = true;
+        revoked.push(k.clone());
+    }
+    if revoked.is_empty() {
+        return Err(ScmError::NotFound(format!("No trusted key matches {who}")));
+    }
+    save_trusted_keys(&keys)?;
+    Ok(revoked)
+}
+
+// ---------------------------------------------------------
+// COMMIT ID
+// ---------------------------------------------------------
+//
+// A commit is serialized as a fixed sequence of fields; free-form text is
+// length-prefixed so no field can spill into the next. The SHA-256 of these
+// bytes is both the commit ID and what gets signed. file_hashes are not
+// listed: they are bound through `root`.
+//
+// Commits older than META_VERSION_CANONICAL keep their six-digit IDs.
+
+fn canonical_commit(meta: &CommitMeta) -> Vec<u8> {
+    let mut out = format!("scm-commit {}\n", meta.version);
+    out.push_str(&format!("root {}\n", meta.root));
+    for parent in &meta.parents {
+        out.push_str(&format!("parent {parent}\n"));
+    }
+    out.push_str(&format!("author {}:{}\n", meta.author.len(), meta.author));
+    out.push_str(&format!("timestamp {}\n", meta.timestamp));
+    out.push_str(&format!("message {}:{}", meta.message.len(), meta.message));
+    out.into_bytes()
+}
+
+fn commit_id(meta: &CommitMeta) -> String {
+    to_hex(&Sha256::digest(canonical_commit(meta)))
+}
+
+/// Abbreviated form of a content-derived ID for display.
+fn short_id(id: &str) -> &str {
+    id.get(..12).unwrap_or(id)
+}
+
+/// Resolve a full commit ID or a unique prefix of one.
+fn resolve_commit(spec: &str) -> Result<String> {
+    if commits_dir().join(spec).join("meta.json").exists() {
+        return Ok(spec.to_string());
+    }
+
+    let mut found = Vec::new();
+    if commits_dir().exists() {
+        for entry in fs::read_dir(commits_dir())? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.starts_with(spec) {
+                found.push(name);
+            }
+        }
+    }
+
+    match found.len() {
+        1 => Ok(found.remove(0)),
+        0 => Err(ScmError::NotFound(format!("Unknown commit {spec}"))),
+        _ => Err(ScmError::Usage(format!("Ambiguous commit {spec}"))),
+    }
+}
+
+// ---------------------------------------------------------
+// TRACKED FILES
+// ---------------------------------------------------------
+
+fn tracked_files() -> Result<Vec<PathBuf>> {
+    let rules = load_ignore_rules()?;
+    let mut files = Vec::new();
+    let root = repo_root();
+    walk_dir(&root, &root, &rules, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn walk_dir(root: &Path, dir: &Path, rules: &[IgnoreRule], files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let rel = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+
+        // Case-insensitive file systems open .scm under any spelling
+        if entry.file_name().eq_ignore_ascii_case(".scm") {
+            continue;
+        }
+
+        // Symlinks are tracked as links, never followed
+        let file_type = entry.file_type()?;
+        let is_dir = file_type.is_dir();
+        if is_ignored(rules, &path_key(&rel), is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            walk_dir(root, &path, rules, files)?;
+        } else if file_type.is_file() || file_type.is_symlink() {
+            files.push(rel);
+        }
+    }
+    Ok(())
+}
+
+/// Repository-relative key for a path, always `/`-separated.
+fn path_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// ---------------------------------------------------------
+// IGNORE RULES
+// ---------------------------------------------------------
+//
+// .scmignore follows gitignore syntax: one glob per line, `#` comments,
+// `!` to re-include, a trailing `/` to match directories only, and a
+// leading or inner `/` to anchor the pattern at the repository root.
+// Patterns without a slash match the name at any depth. The last
+// matching rule wins.
+
+const IGNORE_FILE: &str = ".scmignore";
+
+struct IgnoreRule {
+    line: usize,
+    text: String,
+    glob: String,
+    negate: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+fn load_ignore_rules() -> Result<Vec<IgnoreRule>> {
+    let path = work_path(IGNORE_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let text = fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| parse_ignore_rule(i + 1, line))
+        .collect())
+}
+
+fn parse_ignore_rule(line: usize, raw: &str) -> Option<IgnoreRule> {
+    let text = raw.trim_end();
+    if text.is_empty() || text.starts_with('#') {
+        return None;
+    }
+
+    let mut glob = text;
+    let negate = glob.starts_with('!');
+    if negate {
+        glob = &glob[1..];
+    }
+    // "\#" and "\!" escape a literal leading character
+    if glob.starts_with("\\#") || glob.starts_with("\\!") {
+        glob = &glob[1..];
+    }
+
+    let dir_only = glob.ends_with('/');
+    let glob = glob.trim_end_matches('/');
+    let anchored = glob.contains('/');
+    let glob = glob.trim_start_matches('/');
+
+    if glob.is_empty() {
+        return None;
+    }
+
+    Some(IgnoreRule {
+        line,
+        text: text.to_string(),
+        glob: glob.to_string(),
+        negate,
+        dir_only,
+        anchored,
+    })
+}
+
+fn rule_matches(rule: &IgnoreRule, path: &str, is_dir: bool) -> bool {
+    if rule.dir_only && !is_dir {
+        return false;
+    }
+
+    if rule.anchored {
+        glob_match(rule.glob.as_bytes(), path.as_bytes())
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        glob_match(rule.glob.as_bytes(), name.as_bytes())
+    }
+}
+
+/// Last rule matching `path` itself (not its parents), if any.
+fn last_match<'a>(rules: &'a [IgnoreRule], path: &str, is_dir: bool) -> Option<&'a IgnoreRule> {
+    rules.iter().rev().find(|r| rule_matches(r, path, is_dir))
+}
+
+fn is_ignored(rules: &[IgnoreRule], path: &str, is_dir: bool) -> bool {
+    last_match(rules, path, is_dir).is_some_and(|r| !r.negate)
+}
+
+/// The rule that decides `path`, checking parent directories first: once a
+/// directory is excluded nothing inside it can be re-included.
+fn explain_ignore<'a>(rules: &'a [IgnoreRule], path: &str, is_dir: bool) -> Option<&'a IgnoreRule> {
+    let parts: Vec<&str> = path.split('/').collect();
+
+    for i in 1..parts.len() {
+        let parent = parts[..i].join("/");
+        if let Some(rule) = last_match(rules, &parent, true) {
+            if !rule.negate {
+                return Some(rule);
+            }
+        }
+    }
+
+    last_match(rules, path, is_dir)
+}
+
+/// Whether a path is ignored, and which rule decides it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IgnoreStatus {
+    /// The path is the repository's own .scm directory or inside it.
+    Metadata,
+    /// Rule `text` on line `line` of .scmignore ignores it.
+    Ignored { line: usize, text: String },
+    /// Negated rule `text` on line `line` re-includes it.
+    Reincluded { line: usize, text: String },
+    NotIgnored,
+}
+
+fn ignore_status(key: &str, is_dir: bool) -> Result<IgnoreStatus> {
+    if key == ".scm" || key.starts_with(".scm/") {
+        return Ok(IgnoreStatus::Metadata);
+    }
+
+    let rules = load_ignore_rules()?;
+    Ok(match explain_ignore(&rules, key, is_dir) {
+        Some(rule) if rule.negate => IgnoreStatus::Reincluded { line: rule.line, text: rule.text.clone() },
+        Some(rule) => IgnoreStatus::Ignored { line: rule.line, text: rule.text.clone() },
+        None => IgnoreStatus::NotIgnored,
+    })
+}
+
+fn glob_match(pat: &[u8], text: &[u8]) -> bool {
+    match pat.first() {
+        None => text.is_empty(),
+        Some(b'*') if pat.get(1) == Some(&b'*') => {
+            let rest = &pat[2..];
+            // "**/" may also match zero directories
+            if rest.first() == Some(&b'/') && glob_match(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pat[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => {
+            !text.is_empty() && text[0] != b'/' && glob_match(&pat[1..], &text[1..])
+        }
+        Some(b'[') => match (text.first(), match_class(&pat[1..])) {
+            (Some(&c), Some((matches, len))) if c != b'/' => {
+                matches(c) && glob_match(&pat[1 + len..], &text[1..])
+            }
+            (Some(&c), None) => c == b'[' && glob_match(&pat[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'\\') if pat.len() > 1 => {
+            text.first() == Some(&pat[1]) && glob_match(&pat[2..], &text[1..])
+        }
+        Some(&c) => text.first() == Some(&c) && glob_match(&pat[1..], &text[1..]),
+    }
+}
+
+/// Parse a `[...]` class (after the `[`). Returns a matcher and the number
+/// of pattern bytes consumed including the closing `]`.
+fn match_class(pat: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, usize)> {
+    let mut i = 0;
+    let negate = matches!(pat.first(), Some(b'!') | Some(b'^'));
+    if negate {
+        i += 1;
+    }
+
+    let start = i;
+    // a ']' right after the opening bracket is literal
+    if pat.get(i) == Some(&b']') {
+        i += 1;
+    }
+    while i < pat.len() && pat[i] != b']' {
+        i += 1;
+    }
+    if i >= pat.len() {
+        return None;
+    }
+
+    let body = &pat[start..i];
+    let matcher = move |c: u8| {
+        let mut j = 0;
+        let mut found = false;
+        while j < body.len() {
+            if j + 2 < body.len() && body[j + 1] == b'-' {
+                found |= body[j] <= c && c <= body[j + 2];
+                j += 3;
+            } else {
+                found |= body[j] == c;
+                j += 1;
+            }
+        }
+        found != negate
+    };
+
+    Some((matcher, i + 1))
+}
+
+// ---------------------------------------------------------
+// OBJECT STORE
+// ---------------------------------------------------------
+//
+// File contents live in .scm/objects/ab/cdef..., keyed by the SHA-256 of
+// the bytes. Commits only reference blobs by hash, so an unchanged file is
+// stored once no matter how many commits include it.
+//
+// On disk an object is zlib-compressed behind OBJECT_ZLIB, or, after
+// `scm gc --delta`, a compressed delta against another object behind
+// OBJECT_DELTA. Files with neither prefix were written uncompressed by
+// older versions. read_object hides all of this.
+
+const OBJECT_ZLIB: &[u8] = b"\0scmz\n";
+const OBJECT_DELTA: &[u8] = b"\0scmd\n";
+
+fn object_path(hash: &str) -> PathBuf {
+    object_path_in(&objects_dir(), hash)
+}
+
+fn object_path_in(objects: &Path, hash: &str) -> PathBuf {
+    let (dir, rest) = hash.split_at_checked(2).unwrap_or((hash, ""));
+    objects.join(dir).join(rest)
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+fn encode_full(data: &[u8]) -> Vec<u8> {
+    [OBJECT_ZLIB, &compress(data)].concat()
+}
+
+fn encode_delta(base: &str, delta: &[u8]) -> Vec<u8> {
+    [OBJECT_DELTA, base.as_bytes(), b"\n", &compress(delta)].concat()
+}
+
+/// Store `data` under `hash` unless it is already there.
+fn store_object(hash: &str, data: &[u8]) -> Result<()> {
+    let path = object_path(hash);
+    if !path.exists() {
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_atomic(&path, encode_full(data))?;
+    }
+    Ok(())
+}
+
+fn write_object(data: &[u8]) -> Result<String> {
+    let hash = to_hex(&Sha256::digest(data));
+    store_object(&hash, data)?;
+    Ok(hash)
+}
+
+fn read_object(hash: &str) -> Result<Vec<u8>> {
+    read_object_in(&objects_dir(), hash)
+}
+
+fn read_object_in(objects: &Path, hash: &str) -> Result<Vec<u8>> {
+    decode_object(objects, hash, 0)
+}
+
+fn decode_object(objects: &Path, hash: &str, depth: usize) -> Result<Vec<u8>> {
+    let bad = |msg: &str| ScmError::Corrupt(format!("Object {hash}: {msg}"));
+
+    if depth > MAX_DELTA_CHAIN {
+        return Err(bad("delta chain too long"));
+    }
+
+    // A missing object means a damaged store, not a failed read
+    let raw = fs::read(object_path_in(objects, hash)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => bad("missing"),
+        _ => io::Error::new(e.kind(), format!("Object {hash}: {e}")).into(),
+    })?;
+
+    if let Some(rest) = raw.strip_prefix(OBJECT_ZLIB) {
+        return decompress(rest).map_err(|_| bad("corrupt compressed data"));
+    }
+    if let Some(rest) = raw.strip_prefix(OBJECT_DELTA) {
+        if rest.len() < 65 || rest[64] != b'\n' {
+            return Err(bad("malformed delta header"));
+        }
+        let base_hash = std::str::from_utf8(&rest[..64]).map_err(|_| bad("malformed delta header"))?;
+        let base = decode_object(objects, base_hash, depth + 1)?;
+        let delta = decompress(&rest[65..]).map_err(|_| bad("corrupt compressed data"))?;
+        return apply_delta(&base, &delta).ok_or_else(|| bad("corrupt delta"));
+    }
+
+    Ok(raw)
+}
+
+fn store_files(files: &[PathBuf]) -> Result<Snapshot> {
+    let mut out = BTreeMap::new();
+    for f in files {
+        let (data, mode) = read_working_file(f)?;
+        out.insert(path_key(f), Entry { hash: write_object(&data)?, mode: mode.into() });
+    }
+    Ok(out)
+}
+
+/// Contents and mode of a working file. A symlink's contents are its
+/// target.
+fn read_working_file(key: impl AsRef<Path>) -> Result<(Vec<u8>, &'static str)> {
+    let path = work_path(key);
+    let meta = fs::symlink_metadata(&path)?;
+
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(&path)?;
+        #[cfg(unix)]
+        let bytes = std::os::unix::ffi::OsStrExt::as_bytes(target.as_os_str()).to_vec();
+        #[cfg(not(unix))]
+        let bytes = target.to_string_lossy().into_owned().into_bytes();
+        return Ok((bytes, MODE_LINK));
+    }
+
+    #[cfg(unix)]
+    let executable = std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o111 != 0;
+    #[cfg(not(unix))]
+    let executable = false;
+
+    Ok((fs::read(&path)?, if executable { MODE_EXEC } else { MODE_FILE }))
+}
+
+/// Entry a working file would be staged as.
+fn working_entry(key: impl AsRef<Path>) -> Result<Entry> {
+    let (data, mode) = read_working_file(key)?;
+    Ok(Entry { hash: to_hex(&Sha256::digest(data)), mode: mode.into() })
+}
+
+/// Is there a file, or a symlink even if dangling, at `key`?
+fn working_file_exists(key: impl AsRef<Path>) -> bool {
+    fs::symlink_metadata(work_path(key)).is_ok_and(|m| !m.is_dir())
+}
+
+fn remove_working_file(key: impl AsRef<Path>) -> Result<()> {
+    if working_file_exists(&key) {
+        fs::remove_file(work_path(key))?;
+    }
+    Ok(())
+}
+
+/// Recreate a working file as `mode` says: a symlink to `data`, or a file
+/// holding `data` with the executable bits set or cleared. Systems without
+/// symlinks get a file holding the target.
+fn write_working_file(path: &str, data: &[u8], mode: &str) -> Result<()> {
+    let dest = work_path(path);
+    clear_the_way(path)?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Replace rather than write through whatever is there now
+    remove_working_file(path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if mode == MODE_LINK {
+            let target: &std::ffi::OsStr = std::os::unix::ffi::OsStrExt::from_bytes(data);
+            return Ok(std::os::unix::fs::symlink(target, &dest)?);
+        }
+
+        fs::write(&dest, data)?;
+        let mut perms = fs::metadata(&dest)?.permissions();
+        let bits = perms.mode();
+        // Executable for whoever may read it, as git does
+        perms.set_mode(if mode == MODE_EXEC { bits | ((bits & 0o444) >> 2) } else { bits & !0o111 });
+        Ok(fs::set_permissions(&dest, perms)?)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = mode;
+        Ok(fs::write(dest, data)?)
+    }
+}
+
+/// How restore and checkout treat files the target commit would replace
+/// or remove.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RestoreOptions {
+    /// Leave tracked files the target lacks in place instead of removing
+    /// them.
+    pub keep: bool,
+    /// Overwrite or remove files even if they hold changes not in HEAD.
+    pub force: bool,
+}
+
+/// How rm treats the working files of the paths it stops tracking.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RmOptions {
+    /// Leave the files in the working tree.
+    pub cached: bool,
+    /// Delete the files even if they hold changes not in HEAD.
+    pub force: bool,
+}
+
+/// Remove what stands where `key` or its parent directories must go: a
+/// directory in place of the file, or a file or symlink in place of a
+/// directory. Callers check first that none of it holds anything of value
+/// (see `would_lose`).
+fn clear_the_way(key: &str) -> Result<()> {
+    let parents: Vec<&Path> = Path::new(key).ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()).collect();
+    for parent in parents.into_iter().rev() {
+        match fs::symlink_metadata(work_path(parent)) {
+            Ok(m) if !m.is_dir() => fs::remove_file(work_path(parent))?,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    if fs::symlink_metadata(work_path(key)).is_ok_and(|m| m.is_dir()) {
+        fs::remove_dir_all(work_path(key))?;
+    }
+    Ok(())
+}
+
+/// Switch the whole working tree to `meta`, refusing to discard changes.
+fn restore_from(meta: &CommitMeta) -> Result<()> {
+    checkout_snapshot(&meta.snapshot(), &[String::new()], RestoreOptions::default())?;
+    Ok(())
+}
+
+/// Bring the working files under any of `keys` ("" for all of them) to
+/// `target`: remove the tracked files it lacks, then write its files.
+/// Checks every file before touching any. Returns how many files were
+/// written and removed.
+fn checkout_snapshot(target: &Snapshot, keys: &[String], options: RestoreOptions) -> Result<(usize, usize)> {
+    let head = head_snapshot()?;
+    let index = load_index()?;
+    let covered = |k: &String| keys.iter().any(|key| path_covers(key, k));
+
+    let writes: Vec<(&String, &Entry)> = target.iter().filter(|(k, _)| covered(k)).collect();
+    let mut removals: Vec<&String> = Vec::new();
+    if !options.keep {
+        removals = head.keys().chain(index.keys()).filter(|k| covered(k) && !target.contains_key(*k)).collect();
+        removals.sort();
+        removals.dedup();
+        removals.retain(|k| working_file_exists(k));
+    }
+
+    if !options.force {
+        let touched = writes.iter().map(|(k, _)| *k).chain(removals.iter().copied());
+        let removing: BTreeSet<&str> = removals.iter().map(|k| k.as_str()).collect();
+        check_overwrites(touched.map(|k| (k, target.get(k))), &head, &index, &removing)?;
+    }
+
+    // Removals first, so a file can take the place of a directory that
+    // held only removed files, and the other way round
+    for path in &removals {
+        remove_working_file(path)?;
+        prune_empty_dirs(path);
+    }
+    for (path, e) in &writes {
+        write_working_file(path, &read_object(&e.hash)?, &e.mode)?;
+    }
+    Ok((writes.len(), removals.len()))
+}
+
+/// Refuse if replacing any of `changes` (path and new entry, None to
+/// remove) would discard something HEAD doesn't have. Files in `removing`
+/// are removed before anything is written.
+fn check_overwrites<'a>(
+    changes: impl Iterator<Item = (&'a String, Option<&'a Entry>)>,
+    head: &Snapshot,
+    index: &Snapshot,
+    removing: &BTreeSet<&str>,
+) -> Result<()> {
+    let mut at_risk = String::new();
+    for (path, target) in changes {
+        if would_lose(path, head, index, target, removing)? {
+            at_risk.push_str(&format!("\n  {path}"));
+        }
+    }
+    if !at_risk.is_empty() {
+        return Err(ScmError::Refused(format!(
+            "Local changes would be overwritten; commit them or use --force:{at_risk}"
+        )));
+    }
+    Ok(())
+}
+
+/// Would replacing the working file at `path` with `target` (None to
+/// remove it) discard anything? Only if it holds something HEAD doesn't:
+/// an edit, a staged change, or an untracked file in the way. A file or
+/// symlink where a parent directory must go, or a directory where the
+/// file must go, is in the way unless it is made up of files in
+/// `removing`. A missing file has nothing to lose.
+fn would_lose(
+    path: &str,
+    head: &Snapshot,
+    index: &Snapshot,
+    target: Option<&Entry>,
+    removing: &BTreeSet<&str>,
+) -> Result<bool> {
+    for parent in Path::new(path).ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()) {
+        if fs::symlink_metadata(work_path(parent)).is_ok_and(|m| !m.is_dir()) {
+            return Ok(!removing.contains(path_key(parent).as_str()));
+        }
+    }
+
+    match fs::symlink_metadata(work_path(path)) {
+        Err(_) => return Ok(false),
+        Ok(m) if m.is_dir() => {
+            let mut files = Vec::new();
+            walk_dir(&repo_root(), &work_path(path), &[], &mut files)?;
+            return Ok(files.iter().any(|f| !removing.contains(path_key(f).as_str())));
+        }
+        Ok(_) => {}
+    }
+
+    let working = working_entry(path)?;
+    if Some(&working) == target {
+        return Ok(false);
+    }
+    Ok(Some(&working) != head.get(path) || index.get(path) != head.get(path))
+}
+
+/// Remove the directories above `key` that are now empty, stopping at the
+/// first one that is not.
+fn prune_empty_dirs(key: &str) {
+    let mut dir = Path::new(key).parent();
+    while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        if fs::remove_dir(work_path(d)).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+// ---------------------------------------------------------
+// DELTA ENCODING
+// ---------------------------------------------------------
+//
+// A delta rebuilds a target from a base: the target's length, then a run
+// of instructions, each either "copy <offset> <len>" from the base or
+// "insert <len>" followed by literal bytes. Numbers are LEB128 varints.
+// Matches are found by indexing the base in fixed-size blocks.
+
+const DELTA_BLOCK: usize = 16;
+const DELTA_COPY: u8 = 0;
+const DELTA_INSERT: u8 = 1;
+/// Longest chain of deltas gc builds before storing a full copy again.
+const MAX_DELTA_CHAIN: usize = 16;
+
+fn push_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut n: usize = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        n |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+fn push_insert(out: &mut Vec<u8>, literal: &[u8]) {
+    if !literal.is_empty() {
+        out.push(DELTA_INSERT);
+        push_varint(out, literal.len());
+        out.extend_from_slice(literal);
+    }
+}
+
+fn make_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index: HashMap<&[u8], usize> = HashMap::new();
+    for offset in (0..base.len().saturating_sub(DELTA_BLOCK - 1)).step_by(DELTA_BLOCK) {
+        index.entry(&base[offset..offset + DELTA_BLOCK]).or_insert(offset);
+    }
+
+    let mut out = Vec::new();
+    push_varint(&mut out, target.len());
+
+    let mut pos = 0;
+    let mut literal_start = 0;
+
+    while pos + DELTA_BLOCK <= target.len() {
+        let Some(&offset) = index.get(&target[pos..pos + DELTA_BLOCK]) else {
+            pos += 1;
+            continue;
+        };
+
+        let mut len = DELTA_BLOCK;
+        while offset + len < base.len() && pos + len < target.len() && base[offset + len] == target[pos + len] {
+            len += 1;
+        }
+
+        push_insert(&mut out, &target[literal_start..pos]);
+        out.push(DELTA_COPY);
+        push_varint(&mut out, offset);
+        push_varint(&mut out, len);
+
+        pos += len;
+        literal_start = pos;
+    }
+
+    push_insert(&mut out, &target[literal_start..]);
+    out
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos)?;
+    let mut out = Vec::with_capacity(len.min(delta.len().saturating_mul(64)));
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            DELTA_COPY => {
+                let offset = read_varint(delta, &mut pos)?;
+                let n = read_varint(delta, &mut pos)?;
+                out.extend_from_slice(base.get(offset..offset.checked_add(n)?)?);
+            }
+            DELTA_INSERT => {
+                let n = read_varint(delta, &mut pos)?;
+                out.extend_from_slice(delta.get(pos..pos.checked_add(n)?)?);
+                pos += n;
+            }
+            _ => return None,
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+/// Rewrite every object compressed, storing file versions as deltas
+/// against the next newer version of the same path when `use_delta` is
+/// set and that is smaller. Returns how many objects were rewritten and
+/// how many of those became deltas.
+fn repack_objects(use_delta: bool) -> Result<(usize, usize)> {
+    let mut commits = Vec::new();
+    for entry in fs::read_dir(commits_dir())? {
+        let id = entry?.file_name().to_string_lossy().to_string();
+        commits.push(load_meta(&id)?);
+    }
+    commits.sort_by_key(|meta| Reverse(meta.timestamp));
+
+    // Depth of each rewritten object's delta chain; 0 for a full copy
+    let mut depth: HashMap<String, usize> = HashMap::new();
+    let mut newer: BTreeMap<String, String> = BTreeMap::new();
+    let mut deltas = 0;
+
+    for meta in &commits {
+        for (path, hash) in &meta.file_hashes {
+            let base = newer.insert(path.clone(), hash.clone());
+            if depth.contains_key(hash) {
+                continue;
+            }
+
+            let data = read_object(hash)?;
+            let mut encoded = encode_full(&data);
+            let mut chain = 0;
+
+            if let Some(base) = base.filter(|b| use_delta && b != hash && depth[b] < MAX_DELTA_CHAIN) {
+                let base_data = read_object(&base)?;
+                let delta = make_delta(&base_data, &data);
+                let candidate = encode_delta(&base, &delta);
+                if candidate.len() < encoded.len() && apply_delta(&base_data, &delta).as_deref() == Some(&data[..]) {
+                    encoded = candidate;
+                    chain = depth[&base] + 1;
+                    deltas += 1;
+                }
+            }
+
+            write_atomic(&object_path(hash), &encoded)?;
+            depth.insert(hash.clone(), chain);
+        }
+    }
+
+    // Trees, tag objects and anything else no commit lists directly
+    let mut rewritten = depth.len();
+    for dir in fs::read_dir(objects_dir())? {
+        let dir = dir?;
+        if !dir.path().is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(dir.path())? {
+            let entry = entry?;
+            let hash = format!("{}{}", dir.file_name().to_string_lossy(), entry.file_name().to_string_lossy());
+            if depth.contains_key(&hash) {
+                continue;
+            }
+            write_atomic(&object_path(&hash), encode_full(&read_object(&hash)?))?;
+            rewritten += 1;
+        }
+    }
+
+    Ok((rewritten, deltas))
+}
+
+/// What a repack did to the object store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GcReport {
+    /// Objects rewritten, and how many of those became deltas.
+    pub rewritten: usize,
+    pub deltas: usize,
+    /// Size of the object store in bytes before and after.
+    pub before: u64,
+    pub after: u64,
+}
+
+fn gc(use_delta: bool) -> Result<GcReport> {
+    let before = dir_size(&objects_dir())?;
+    let (rewritten, deltas) = repack_objects(use_delta)?;
+    let after = dir_size(&objects_dir())?;
+    Ok(GcReport { rewritten, deltas, before, after })
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        total += if meta.is_dir() { dir_size(&entry.path())? } else { meta.len() };
+    }
+    Ok(total)
+}
+
+// ---------------------------------------------------------
+// TREE OBJECTS
+// ---------------------------------------------------------
+//
+// Every directory is a tree object: one "<mode> <hash> <name>" line per
+// entry, sorted by name. A tree is stored under the Merkle root of its
+// entries, so the root tree's hash covers every file and directory name
+// below it. How that root is computed depends on the commit's version, so
+// every function here takes it.
+
+const MODE_FILE: &str = "100644";
+const MODE_EXEC: &str = "100755";
+const MODE_LINK: &str = "120000";
+const MODE_TREE: &str = "040000";
+
+struct TreeEntry {
+    mode: String,
+    hash: String,
+    name: String,
+}
+
+enum TreeNode {
+    File(Entry),
+    Dir(BTreeMap<String, TreeNode>),
+}
+
+fn entry_leaf(version: u32, e: &TreeEntry) -> String {
+    let line = format!("{} {} {}", e.mode, e.hash, e.name);
+    let mut hasher = Sha256::new();
+    if version >= META_VERSION_MERKLE {
+        hasher.update([MERKLE_LEAF]);
+    }
+    hasher.update(line.as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+fn tree_hash(version: u32, entries: &[TreeEntry]) -> String {
+    let leaves: Vec<String> = entries.iter().map(|e| entry_leaf(version, e)).collect();
+    // An empty tree hashes as the empty string
+    merkle_root(version, &leaves).unwrap_or_else(|| to_hex(&Sha256::digest(b"")))
+}
+
+fn nest_paths(snapshot: &Snapshot) -> Result<BTreeMap<String, TreeNode>> {
+    let mut root = BTreeMap::new();
+
+    for (path, entry) in snapshot {
+        let parts: Vec<&str> = path.split('/').collect();
+        let mut dir = &mut root;
+
+        for part in &parts[..parts.len() - 1] {
+            let node = dir
+                .entry(part.to_string())
+                .or_insert_with(|| TreeNode::Dir(BTreeMap::new()));
+            dir = match node {
+                TreeNode::Dir(children) => children,
+                TreeNode::File(_) => {
+                    return Err(ScmError::Corrupt(format!(
+                        "{path} is listed under a path that is also a file"
+                    )))
+                }
+            };
+        }
+
+        dir.insert(parts[parts.len() - 1].to_string(), TreeNode::File(entry.clone()));
+    }
+
+    Ok(root)
+}
+
+/// Hash a directory level, optionally writing tree objects to the store.
+fn build_tree(version: u32, dir: &BTreeMap<String, TreeNode>, write: bool) -> Result<String> {
+    let mut entries = Vec::new();
+
+    for (name, node) in dir {
+        let (mode, hash) = match node {
+            TreeNode::File(e) => (e.mode.as_str(), e.hash.clone()),
+            TreeNode::Dir(children) => (MODE_TREE, build_tree(version, children, write)?),
+        };
+        entries.push(TreeEntry { mode: mode.into(), hash, name: name.clone() });
+    }
+
+    let hash = tree_hash(version, &entries);
+
+    if write {
+        let body: String = entries
+            .iter()
+            .map(|e| format!("{} {} {}\n", e.mode, e.hash, e.name))
+            .collect();
+        store_object(&hash, body.as_bytes())?;
+    }
+
+    Ok(hash)
+}
+
+fn write_tree(version: u32, snapshot: &Snapshot) -> Result<String> {
+    build_tree(version, &nest_paths(snapshot)?, true)
+}
+
+/// Can `name` be one entry of a tree? Anything else could climb out of
+/// the working tree or into the repository's own files once written, or
+/// would split its tree line in two.
+fn valid_entry_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.eq_ignore_ascii_case(".scm")
+        && !name.contains(['/', '\n'])
+}
+
+/// Is `key` a path a commit may hold: relative, with only valid entry
+/// names between its slashes?
+fn valid_path_key(key: &str) -> bool {
+    key.split('/').all(valid_entry_name)
+}
+
+/// Read a tree object and check that its entries hash to `hash`.
+fn read_tree(version: u32, hash: &str) -> Result<Vec<TreeEntry>> {
+    let text = String::from_utf8(read_object(hash)?)
+        .map_err(|_| ScmError::Corrupt(format!("Tree {hash} is not UTF-8")))?;
+
+    let mut entries = Vec::new();
+    for line in text.split_terminator('\n') {
+        let mut parts = line.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(mode), Some(h), Some(name)) => entries.push(TreeEntry {
+                mode: mode.into(),
+                hash: h.into(),
+                name: name.into(),
+            }),
+            _ => return Err(ScmError::Corrupt(format!("Malformed tree {hash}"))),
+        }
+    }
+
+    if let Some(e) = entries.iter().find(|e| !valid_entry_name(&e.name)) {
+        return Err(ScmError::Corrupt(format!("Tree {hash} has an invalid entry name {:?}", e.name)));
+    }
+
+    // Names must be unique and sorted, as build_tree writes them
+    if entries.windows(2).any(|pair| pair[0].name >= pair[1].name) {
+        return Err(ScmError::Corrupt(format!("Tree {hash} has unsorted or duplicate entries")));
+    }
+
+    if tree_hash(version, &entries) != hash {
+        return Err(ScmError::Corrupt(format!("Tree {hash} does not match its contents")));
+    }
+
+    Ok(entries)
+}
+
+/// Flatten a tree back into a snapshot, verifying every tree on the way.
+fn walk_tree(version: u32, hash: &str, prefix: &str, out: &mut Snapshot) -> Result<()> {
+    for e in read_tree(version, hash)? {
+        let path = if prefix.is_empty() { e.name.clone() } else { format!("{prefix}/{}", e.name) };
+        if e.mode == MODE_TREE {
+            walk_tree(version, &e.hash, &path, out)?;
+        } else {
+            out.insert(path, Entry { hash: e.hash, mode: e.mode });
+        }
+    }
+    Ok(())
+}
+
+fn load_meta(id: &str) -> Result<CommitMeta> {
+    let meta_path = commits_dir().join(id).join("meta.json");
+    let raw = fs::read_to_string(meta_path)
+        .map_err(|e| ScmError::Corrupt(format!("Cannot read commit {id}: {e}")))?;
+    serde_json::from_str(&raw)
+        .map_err(|e| ScmError::Corrupt(format!("Commit {id} has unreadable metadata: {e}")))
+}
+
+// ---------------------------------------------------------
+// LEGACY MIGRATION
+// ---------------------------------------------------------
+//
+// Older repositories kept a full copy of every file next to meta.json in
+// .scm/commits/NNNNNN/. Move those copies into the object store and leave
+// only the metadata behind. Safe to run repeatedly.
+
+fn migrate_legacy_commits() -> Result<()> {
+    if !commits_dir().exists() {
+        return Ok(());
+    }
+
+    for commit in fs::read_dir(commits_dir())? {
+        let commit = commit?;
+        if !commit.path().is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(commit.path())? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if !path.is_file() || entry.file_name() == "meta.json" {
+                continue;
+            }
+
+            if !objects_dir().exists() { fs::create_dir(objects_dir())?; }
+            write_object(&fs::read(&path)?)?;
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------
+// REFS + HEAD
+// ---------------------------------------------------------
+//
+// Branches are files under .scm/refs/heads/ holding a commit ID. .scm/HEAD
+// is either "ref: refs/heads/<branch>" or, when detached, a bare commit ID.
+// Commits point at their parents, so history is walked from a ref rather
+// than read from a list.
+
+const DEFAULT_BRANCH: &str = "main";
+
+/// What HEAD points at.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Head {
+    /// A branch, which has no commits yet in a new repository.
+    Branch(String),
+    /// A commit outside any branch.
+    Detached(ObjectId),
+}
+
+fn read_head() -> Result<Head> {
+    if !head_path().exists() {
+        return Ok(Head::Branch(DEFAULT_BRANCH.into()));
+    }
+
+    let text = fs::read_to_string(head_path())?;
+    let text = text.trim();
+    Ok(match text.strip_prefix("ref: refs/heads/") {
+        Some(branch) => Head::Branch(branch.to_string()),
+        None => Head::Detached(ObjectId(text.to_string())),
+    })
+}
+
+fn write_head(head: &Head) -> Result<()> {
+    match head {
+        Head::Branch(name) => write_atomic(&head_path(), format!("ref: refs/heads/{name}\n")),
+        Head::Detached(id) => write_atomic(&head_path(), format!("{id}\n")),
+    }
+}
+
+fn branch_tip(name: &str) -> Result<Option<String>> {
+    let path = heads_dir().join(name);
+    // Anything else could name a file outside refs/heads
+    if !valid_ref_name(name) || !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?.trim().to_string()))
+}
+
+fn set_branch(name: &str, id: &str) -> Result<()> {
+    fs::create_dir_all(heads_dir())?;
+    write_atomic(&heads_dir().join(name), format!("{id}\n"))
+}
+
+fn valid_ref_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && !name.starts_with('.')
+        && name != "HEAD"
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// The commit HEAD points at, if any.
+fn head_commit() -> Result<Option<String>> {
+    match read_head()? {
+        Head::Branch(name) => branch_tip(&name),
+        Head::Detached(id) => Ok(Some(id.0)),
+    }
+}
+
+/// Move HEAD to `id`: advances the current branch, or the detached HEAD.
+fn update_head(id: &str) -> Result<()> {
+    match read_head()? {
+        Head::Branch(name) => set_branch(&name, id),
+        Head::Detached(_) => write_atomic(&head_path(), format!("{id}\n")),
+    }
+}
+
+/// Every branch name, sorted.
+fn branch_names() -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    if heads_dir().exists() {
+        for entry in fs::read_dir(heads_dir())? {
+            names.push(entry?.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Create branch `name` at `rev`, or at HEAD if None. Returns its tip.
+fn create_branch(name: &str, rev: Option<&str>) -> Result<String> {
+    if !valid_ref_name(name) {
+        return Err(ScmError::Usage(format!("Invalid branch name {name}")));
+    }
+    if branch_tip(name)?.is_some() {
+        return Err(ScmError::Refused(format!("Branch {name} already exists")));
+    }
+    let target = match rev {
+        Some(rev) => resolve_rev(rev)?,
+        None => head_commit()?.ok_or_else(|| ScmError::NotFound("No commits yet.".into()))?,
+    };
+    set_branch(name, &target)?;
+    Ok(target)
+}
+
+fn delete_branch(name: &str) -> Result<()> {
+    if !valid_ref_name(name) {
+        return Err(ScmError::Usage(format!("Invalid branch name {name}")));
+    }
+    if matches!(read_head()?, Head::Branch(ref b) if b == name) {
+        return Err(ScmError::Refused(format!("Cannot delete the checked-out branch {name}")));
+    }
+    if branch_tip(name)?.is_none() {
+        return Err(ScmError::NotFound(format!("No such branch {name}")));
+    }
+    Ok(fs::remove_file(heads_dir().join(name))?)
+}
+
+/// Switch to branch or commit `spec` once it has been verified: bring the
+/// working tree and index to it as `options` allow, drop any merge in
+/// progress, and point HEAD at it. Returns where HEAD now points.
+fn checkout_rev(spec: &str, options: RestoreOptions) -> Result<Head> {
+    let branch = valid_ref_name(spec) && branch_tip(spec)?.is_some();
+    let target = resolve_rev(spec)?;
+    let meta = load_meta(&target)?;
+
+    verify_meta(&target, &meta)?;
+
+    // A merge in progress belongs to the commit being left behind
+    if merge_msg_path().exists() && !options.force {
+        return Err(ScmError::Refused(
+            "A merge or revert is in progress; commit it, run `scm merge --abort`, or use --force.".into(),
+        ));
+    }
+
+    checkout_snapshot(&meta.snapshot(), &[String::new()], options)?;
+    save_index(&meta.snapshot())?;
+    clear_merge_state()?;
+
+    let head = if branch { Head::Branch(spec.to_string()) } else { Head::Detached(ObjectId(target)) };
+    write_head(&head)?;
+    Ok(head)
+}
+
+/// Create branch `name` at HEAD and switch to it. The working tree and
+/// index stay as they are.
+fn checkout_new_branch(name: &str) -> Result<()> {
+    if !valid_ref_name(name) || branch_tip(name)?.is_some() {
+        return Err(ScmError::Usage(format!("Cannot create branch {name}")));
+    }
+    let Some(head) = head_commit()? else {
+        return Err(ScmError::NotFound("No commits yet.".into()));
+    };
+    set_branch(name, &head)?;
+    write_head(&Head::Branch(name.to_string()))
+}
+
+/// Resolve "HEAD", a branch name, or a (prefix of a) commit ID.
+fn resolve_rev(spec: &str) -> Result<String> {
+    if spec == "HEAD" {
+        return head_commit()?
+            .ok_or_else(|| ScmError::NotFound("HEAD has no commits yet".into()));
+    }
+    if valid_ref_name(spec) {
+        if let Some(id) = branch_tip(spec)? {
+            return Ok(id);
+        }
+        if let Some(tag) = read_tag(spec)? {
+            return Ok(tag.target);
+        }
+    }
+    resolve_commit(spec)
+}
+
+/// Parents of a commit. Commits that predate parent pointers take their
+/// predecessor from the legacy log.
+fn commit_parents(id: &str, meta: &CommitMeta) -> Result<Vec<String>> {
+    if meta.version >= META_VERSION_HEADER || !legacy_log_path().exists() {
+        return Ok(meta.parents.clone());
+    }
+
+    let text = fs::read_to_string(legacy_log_path())?;
+    let list: Vec<&str> = text.lines().collect();
+    Ok(match list.iter().position(|l| *l == id) {
+        Some(i) if i > 0 => vec![list[i - 1].to_string()],
+        _ => Vec::new(),
+    })
+}
+
+/// Commits waiting to be visited: newest first, ties going to the
+/// earliest queued.
+struct CommitQueue {
+    heap: BinaryHeap<(u64, Reverse<usize>)>,
+    slots: Vec<Option<(String, CommitMeta)>>,
+    seen: HashSet<String>,
+}
+
+impl CommitQueue {
+    fn new() -> Self {
+        CommitQueue { heap: BinaryHeap::new(), slots: Vec::new(), seen: HashSet::new() }
+    }
+
+    /// Note `id` as seen; false if it already was.
+    fn mark(&mut self, id: &str) -> bool {
+        self.seen.insert(id.to_string())
+    }
+
+    fn push(&mut self, id: String, meta: CommitMeta) {
+        self.heap.push((meta.timestamp, Reverse(self.slots.len())));
+        self.slots.push(Some((id, meta)));
+    }
+
+    fn pop(&mut self) -> Option<(String, CommitMeta)> {
+        let (_, Reverse(slot)) = self.heap.pop()?;
+        self.slots[slot].take()
+    }
+}
+
+/// Every commit reachable from `starts`, newest first.
+fn history(starts: &[String]) -> Result<Vec<(String, CommitMeta)>> {
+    let mut out = Vec::new();
+    let mut queue = CommitQueue::new();
+
+    for id in starts {
+        if queue.mark(id) {
+            queue.push(id.clone(), load_meta(id)?);
+        }
+    }
+
+    while let Some((id, meta)) = queue.pop() {
+        for parent in commit_parents(&id, &meta)? {
+            if queue.mark(&parent) {
+                let parent_meta = load_meta(&parent)?;
+                queue.push(parent, parent_meta);
+            }
+        }
+        out.push((id, meta));
+    }
+
+    Ok(out)
+}
+
+/// Convert a repository that only has the legacy log into refs: the last
+/// logged commit becomes the tip of the default branch.
+fn migrate_log_to_refs() -> Result<()> {
+    if head_path().exists() || !legacy_log_path().exists() {
+        return Ok(());
+    }
+
+    let text = fs::read_to_string(legacy_log_path())?;
+    if let Some(last) = text.lines().last() {
+        set_branch(DEFAULT_BRANCH, last)?;
+    }
+    write_atomic(&head_path(), format!("ref: refs/heads/{DEFAULT_BRANCH}\n"))
+}
+
+// ---------------------------------------------------------
+// TAGS
+// ---------------------------------------------------------
+//
+// .scm/refs/tags/<name> holds either a commit ID (lightweight tag) or the
+// hash of a tag object in the object store (annotated tag). Tag objects
+// are signed like commits: the signature covers the SHA-256 of a canonical
+// serialization, prefixed so it can never be mistaken for a commit.
+
+#[derive(Serialize, Deserialize)]
+struct TagObject {
+    name: String,
+    target: String,
+    #[serde(default)]
+    tagger: String,
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    public_key: String,
+    #[serde(default)]
+    signature: String,
+}
+
+impl TagObject {
+    fn annotated(&self) -> bool {
+        !self.signature.is_empty()
+    }
+}
+
+fn canonical_tag(tag: &TagObject) -> Vec<u8> {
+    let mut out = String::from("scm-tag 1\n");
+    out.push_str(&format!("object {}\n", tag.target));
+    out.push_str(&format!("tag {}:{}\n", tag.name.len(), tag.name));
+    out.push_str(&format!("tagger {}:{}\n", tag.tagger.len(), tag.tagger));
+    out.push_str(&format!("timestamp {}\n", tag.timestamp));
+    out.push_str(&format!("message {}:{}", tag.message.len(), tag.message));
+    out.into_bytes()
+}
+
+fn tag_names() -> Result<Vec<String>> {
+    if !tags_dir().exists() {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = fs::read_dir(tags_dir())?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Load a tag; lightweight tags come back unsigned with only `target` set.
+fn read_tag(name: &str) -> Result<Option<TagObject>> {
+    let path = tags_dir().join(name);
+    // Anything else could name a file outside refs/tags
+    if !valid_ref_name(name) || !path.exists() {
+        return Ok(None);
+    }
+
+    let id = fs::read_to_string(path)?.trim().to_string();
+    if commits_dir().join(&id).join("meta.json").exists() {
+        return Ok(Some(TagObject {
+            name: name.to_string(),
+            target: id,
+            tagger: String::new(),
+            timestamp: 0,
+            message: String::new(),
+            public_key: String::new(),
+            signature: String::new(),
+        }));
+    }
+
+    let data = read_object(&id)?;
+    if to_hex(&Sha256::digest(&data)) != id {
+        return Err(ScmError::Corrupt(format!("Tag object {id} is corrupt")));
+    }
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(ScmError::from)
+}
+
+/// A tag and the commit it names.
+#[derive(Clone, Debug)]
+pub struct Tag {
+    pub name: String,
+    pub target: ObjectId,
+    /// The message of an annotated (signed) tag; None for a lightweight
+    /// one.
+    pub message: Option<String>,
+}
+
+fn list_tags() -> Result<Vec<Tag>> {
+    let mut out = Vec::new();
+    for name in tag_names()? {
+        if let Some(tag) = read_tag(&name)? {
+            let message = tag.annotated().then_some(tag.message);
+            out.push(Tag { name, target: ObjectId(tag.target), message });
+        }
+    }
+    Ok(out)
+}
+
+/// Tag `rev` as `name`. With a message the tag is annotated: a tag object
+/// signed with the local key. Returns the tagged commit.
+fn create_tag(name: &str, rev: &str, message: Option<&str>) -> Result<String> {
+    if !valid_ref_name(name) {
+        return Err(ScmError::Usage(format!("Invalid tag name {name}")));
+    }
+    if read_tag(name)?.is_some() {
+        return Err(ScmError::Refused(format!("Tag {name} already exists")));
+    }
+    let target = resolve_rev(rev)?;
+
+    let id = match message {
+        Some(message) => {
+            let mut tag = TagObject {
+                name: name.to_string(),
+                target: target.clone(),
+                tagger: author_identity()?,
+                timestamp: now_secs(),
+                message: message.to_string(),
+                public_key: String::new(),
+                signature: String::new(),
+            };
+            let sk = load_or_generate_signing_key()?;
+            tag.public_key = to_hex(sk.verifying_key().as_bytes());
+            tag.signature = to_hex(&sk.sign(&Sha256::digest(canonical_tag(&tag))).to_bytes());
+            write_object(serde_json::to_string_pretty(&tag).unwrap().as_bytes())?
+        }
+        None => target.clone(),
+    };
+
+    fs::create_dir_all(tags_dir())?;
+    write_atomic(&tags_dir().join(name), format!("{id}\n"))?;
+    Ok(target)
+}
+
+fn delete_tag(name: &str) -> Result<()> {
+    if !valid_ref_name(name) {
+        return Err(ScmError::Usage(format!("Invalid tag name {name}")));
+    }
+    if read_tag(name)?.is_none() {
+        return Err(ScmError::NotFound(format!("No such tag {name}")));
+    }
+    Ok(fs::remove_file(tags_dir().join(name))?)
+}
+
+/// Check annotated tag `name` and the commit it names. Returns the tag's
+/// trusted signer and the commit.
+fn verify_tag(name: &str) -> Result<(String, String)> {
+    let Some(tag) = read_tag(name)? else {
+        return Err(ScmError::NotFound(format!("No such tag {name}")));
+    };
+    if !tag.annotated() {
+        return Err(ScmError::Usage(format!("{name} is a lightweight tag and carries no signature")));
+    }
+    let signer = check_tag(&tag, name)?.map_err(ScmError::Verification)?;
+    verify_meta(&tag.target, &load_meta(&tag.target)?)?;
+    Ok((signer, tag.target))
+}
+
+/// Check an annotated tag stored under `ref_name`: its name, signature
+/// and signer. Returns the signer's name.
+fn check_tag(tag: &TagObject, ref_name: &str) -> Result<Result<String, String>> {
+    // Otherwise a signed tag could be copied under another name
+    if tag.name != ref_name {
+        return Ok(Err(format!("Tag object is named {}, not {ref_name}.", tag.name)));
+    }
+
+    let Ok(vk) = parse_verifying_key(&tag.public_key) else {
+        return Ok(Err("Malformed public key.".into()));
+    };
+
+    let payload = Sha256::digest(canonical_tag(tag));
+    if !signature_valid(&vk, &payload, &tag.signature) {
+        return Ok(Err("Tag signature verification failed.".into()));
+    }
+
+    Ok(trusted_signer(&load_trusted_keys()?, &tag.public_key))
+}
+
+// ---------------------------------------------------------
+// INDEX (STAGING AREA)
+// ---------------------------------------------------------
+//
+// .scm/index maps path -> blob hash and mode for exactly what the next
+// commit will contain. Blobs are written to the object store when a path is staged.
+// Without an index file the last commit's snapshot is the staged state.
+
+/// On-disk form of the index. Older versions stored the path -> hash map
+/// alone.
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    files: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    modes: BTreeMap<String, String>,
+}
+
+fn load_index() -> Result<Snapshot> {
+    if index_path().exists() {
+        let text = fs::read_to_string(index_path())?;
+        let index = match serde_json::from_str::<IndexFile>(&text) {
+            Ok(index) => index,
+            Err(_) => IndexFile { files: serde_json::from_str(&text)?, modes: BTreeMap::new() },
+        };
+        return Ok(join_snapshot(&index.files, &index.modes));
+    }
+
+    match head_commit()? {
+        Some(id) => Ok(load_meta(&id)?.snapshot()),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// What HEAD's commit holds; empty before the first commit.
+fn head_snapshot() -> Result<Snapshot> {
+    match head_commit()? {
+        Some(id) => Ok(load_meta(&id)?.snapshot()),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// The working tree as a snapshot: every file not ignored, plus tracked
+/// files (in the index or HEAD) that now match an ignore rule, since
+/// ignoring only applies to untracked files.
+fn working_snapshot() -> Result<Snapshot> {
+    let mut keys: BTreeSet<String> = tracked_files()?.iter().map(|f| path_key(f)).collect();
+    let (index, head) = (load_index()?, head_snapshot()?);
+    keys.extend(index.keys().chain(head.keys()).filter(|k| working_file_exists(k)).cloned());
+
+    let mut working = BTreeMap::new();
+    for key in keys {
+        let entry = working_entry(&key)?;
+        working.insert(key, entry);
+    }
+    Ok(working)
+}
+
+/// How a file differs between two states.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// The index against HEAD and the working tree against the index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Status {
+    /// Whether a merge or revert stopped by conflicts is in progress, and
+    /// the paths in it still to be fixed and added.
+    pub merging: bool,
+    pub conflicts: Vec<String>,
+    /// Changes that the next commit will record.
+    pub staged: Vec<(Change, String)>,
+    /// Tracked files that differ from the index.
+    pub unstaged: Vec<(Change, String)>,
+    /// Files neither tracked nor ignored.
+    pub untracked: Vec<String>,
+}
+
+impl Status {
+    pub fn is_clean(&self) -> bool {
+        self.staged.is_empty() && self.unstaged.is_empty() && self.untracked.is_empty()
+    }
+}
+
+fn working_status() -> Result<Status> {
+    let head = head_snapshot()?;
+    let index = load_index()?;
+    let files = tracked_files()?;
+    let working = working_snapshot()?;
+
+    let mut staged = Vec::new();
+    for (path, entry) in &index {
+        match head.get(path) {
+            None => staged.push((Change::Added, path.clone())),
+            Some(e) if e != entry => staged.push((Change::Modified, path.clone())),
+            _ => {}
+        }
+    }
+    for path in head.keys() {
+        if !index.contains_key(path) {
+            staged.push((Change::Deleted, path.clone()));
+        }
+    }
+
+    let mut unstaged = Vec::new();
+    for (path, entry) in &index {
+        match working.get(path) {
+            None if !working_file_exists(path) => unstaged.push((Change::Deleted, path.clone())),
+            Some(e) if e != entry => unstaged.push((Change::Modified, path.clone())),
+            _ => {}
+        }
+    }
+
+    // Only files the ignore rules let through count as untracked
+    let untracked = files.iter().map(|f| path_key(f)).filter(|p| !index.contains_key(p)).collect();
+
+    Ok(Status { merging: merge_msg_path().exists(), conflicts: load_conflicts()?, staged, unstaged, untracked })
+}
+
+fn save_index(index: &Snapshot) -> Result<()> {
+    let (files, modes) = split_snapshot(index);
+    write_atomic(&index_path(), serde_json::to_string_pretty(&IndexFile { files, modes }).unwrap())
+}
+
+/// Stage everything under `key`: the current contents of `files` below it
+/// and the removal of indexed paths that no longer exist. False if
+/// nothing matched.
+fn stage_path(index: &mut Snapshot, files: &[PathBuf], key: &str) -> Result<bool> {
+    let matched: Vec<PathBuf> = files
+        .iter()
+        .filter(|f| path_covers(key, &path_key(f)))
+        .cloned()
+        .collect();
+
+    let gone: Vec<String> = index
+        .keys()
+        .filter(|k| path_covers(key, k) && !working_file_exists(k))
+        .cloned()
+        .collect();
+
+    if matched.is_empty() && gone.is_empty() {
+        return Ok(false);
+    }
+    if let Some(bad) = matched.iter().map(|f| path_key(f)).find(|k| !valid_path_key(k)) {
+        if bad.contains('\n') {
+            return Err(ScmError::Refused(format!("{bad:?}: file names containing a newline cannot be committed")));
+        }
+        return Err(ScmError::Refused(format!("{bad:?} is not a path scm can commit")));
+    }
+
+    index.extend(store_files(&matched)?);
+    for k in gone {
+        index.remove(&k);
+    }
+    Ok(true)
+}
+
+/// Stage `paths`, each given as typed along with its repository key. An
+/// ignored file or a path matching nothing fails the whole call before
+/// the index is touched.
+fn add_paths(paths: &[(&str, String)]) -> Result<()> {
+    let rules = load_ignore_rules()?;
+    let files = tracked_files()?;
+    let mut index = load_index()?;
+
+    for (arg, key) in paths {
+        if work_path(key).is_file() && is_ignored(&rules, key, false) {
+            return Err(ScmError::NotFound(format!("{arg}: ignored by {IGNORE_FILE}, not added")));
+        }
+        if !stage_path(&mut index, &files, key)? {
+            return Err(ScmError::NotFound(format!("{arg}: did not match any files")));
+        }
+    }
+
+    for (_, key) in paths {
+        resolve_conflicts(key)?;
+    }
+    save_index(&index)
+}
+
+/// Stop tracking `paths` (as typed, with their keys) and, unless
+/// `options.cached`, delete them from the working tree. Every path must
+/// match a tracked file, and without `force` every file to delete must
+/// match both the index and HEAD, before anything is removed. Returns the
+/// keys removed.
+fn rm_paths(paths: &[(&str, String)], options: RmOptions) -> Result<Vec<String>> {
+    let mut index = load_index()?;
+
+    for (arg, key) in paths {
+        if !index.keys().any(|k| path_covers(key, k)) {
+            return Err(ScmError::NotFound(format!("{arg}: not tracked")));
+        }
+    }
+
+    if !options.cached && !options.force {
+        let head = head_snapshot()?;
+        let mut changed = Vec::new();
+        for (k, staged) in index.iter().filter(|(k, _)| paths.iter().any(|(_, key)| path_covers(key, k))) {
+            if working_file_exists(k) && (working_entry(k)? != *staged || head.get(k) != Some(staged)) {
+                changed.push(k.as_str());
+            }
+        }
+        if !changed.is_empty() {
+            return Err(ScmError::Refused(format!(
+                "Files have changes not in HEAD; commit them, use --cached, or use --force:\n  {}",
+                changed.join("\n  ")
+            )));
+        }
+    }
+
+    let mut removed = Vec::new();
+    for (_, key) in paths {
+        resolve_conflicts(key)?;
+        let matched: Vec<String> = index.keys().filter(|k| path_covers(key, k)).cloned().collect();
+        for k in matched {
+            index.remove(&k);
+            if !options.cached {
+                remove_working_file(&k)?;
+            }
+            removed.push(k);
+        }
+    }
+
+    save_index(&index)?;
+    Ok(removed)
+}
+
+/// Does `path` name `key` itself or a directory containing it?
+fn path_covers(path: &str, key: &str) -> bool {
+    path.is_empty() || key == path || key.starts_with(&format!("{path}/"))
+}
+
+/// Normalize a command-line path into a repository key ("" for the root).
+/// Paths outside the repository come back absolute and match nothing.
+fn arg_key(arg: &str) -> String {
+    let full = normalize(&user_path(arg));
+    let root = normalize(&repo_root());
+    match full.strip_prefix(&root) {
+        Ok(rel) => path_key(rel),
+        Err(_) => full.to_string_lossy().to_string(),
+    }
+}
+
+// ---------------------------------------------------------
+// CONFIG + AUTHOR
+// ---------------------------------------------------------
+//
+// .scm/config holds "key = value" lines. The author is taken from
+// SCM_AUTHOR_NAME / SCM_AUTHOR_EMAIL if set, then user.name / user.email
+// from the config, then the login name.
+
+fn load_config() -> Result<BTreeMap<String, String>> {
+    let mut out = BTreeMap::new();
+    if !config_path().exists() {
+        return Ok(out);
+    }
+
+    for line in fs::read_to_string(config_path())?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((k, v)) = line.split_once('=') {
+            out.insert(k.trim().to_string(), v.trim().to_string());
+        }
+    }
+    Ok(out)
+}
+
+fn save_config(config: &BTreeMap<String, String>) -> Result<()> {
+    let text: String = config.iter().map(|(k, v)| format!("{k} = {v}\n")).collect();
+    write_atomic(&config_path(), text)
+}
+
+fn author_identity() -> Result<String> {
+    if let Some(author) = identity().author {
+        return Ok(author);
+    }
+    let config = load_config()?;
+
+    let name = env::var("SCM_AUTHOR_NAME")
+        .ok()
+        .or_else(|| config.get("user.name").cloned())
+        .or_else(|| env::var("USER").ok())
+        .unwrap_or_else(|| "unknown".into());
+    let email = env::var("SCM_AUTHOR_EMAIL")
+        .ok()
+        .or_else(|| config.get("user.email").cloned());
+
+    Ok(match email {
+        Some(email) => format!("{name} <{email}>"),
+        None => name,
+    })
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// "YYYY-MM-DD HH:MM:SS +0000" for a Unix timestamp.
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // civil-from-days (Howard Hinnant)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} +0000",
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+// ---------------------------------------------------------
+// HASHING + MERKLE TREE
+// ---------------------------------------------------------
+
+fn compute_hashes(file_hashes: &BTreeMap<String, String>) -> Result<BTreeMap<String, String>> {
+    let mut out = BTreeMap::new();
+
+    for (name, hash) in file_hashes {
+        let data = read_object(hash)?;
+        out.insert(name.clone(), to_hex(&Sha256::digest(&data)));
+    }
+
+    Ok(out)
+}
+
+/// Domain prefixes for META_VERSION_MERKLE trees, so a leaf can never be
+/// read as an interior node or the other way round.
+const MERKLE_LEAF: u8 = 0x00;
+const MERKLE_NODE: u8 = 0x01;
+
+fn merkle_node(version: u32, left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    if version >= META_VERSION_MERKLE {
+        hasher.update([MERKLE_NODE]);
+    }
+    // Malformed hashes only come from damaged metadata; hashing them as empty
+    // yields a root that simply fails to match.
+    hasher.update(from_hex(left).unwrap_or_default());
+    hasher.update(from_hex(right).unwrap_or_default());
+    to_hex(&hasher.finalize())
+}
+
+/// Every layer of the tree, leaves first, root last. An odd node at the
+/// end of a layer is promoted unchanged; older versions paired it with
+/// itself.
+fn merkle_layers(version: u32, hashes: &[String]) -> Vec<Vec<String>> {
+    let mut layers = vec![hashes.to_vec()];
+
+    while layers.last().unwrap().len() > 1 {
+        let layer = layers.last().unwrap();
+        let next = layer
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => merkle_node(version, left, right),
+                [odd] if version >= META_VERSION_MERKLE => odd.clone(),
+                [odd] => merkle_node(version, odd, odd),
+                _ => unreachable!(),
+            })
+            .collect();
+        layers.push(next);
+    }
+
+    layers
+}
+
+/// None for no hashes at all.
+fn merkle_root(version: u32, hashes: &[String]) -> Option<String> {
+    merkle_layers(version, hashes).pop().unwrap().first().cloned()
+}
+
+/// Sibling hashes from leaf `index` up to the root. Layers where the node
+/// is promoted contribute no step.
+fn merkle_path(version: u32, hashes: &[String], mut index: usize) -> Vec<ProofStep> {
+    let layers = merkle_layers(version, hashes);
+    let mut path = Vec::new();
+
+    for layer in &layers[..layers.len() - 1] {
+        if index.is_multiple_of(2) {
+            match layer.get(index + 1) {
+                Some(sibling) => path.push(ProofStep { hash: sibling.clone(), left: false }),
+                None if version >= META_VERSION_MERKLE => {}
+                None => path.push(ProofStep { hash: layer[index].clone(), left: false }),
+            }
+        } else {
+            path.push(ProofStep { hash: layer[index - 1].clone(), left: true });
+        }
+        index /= 2;
+    }
+
+    path
+}
+
+fn merkle_fold(version: u32, leaf: &str, path: &[ProofStep]) -> Result<String> {
+    let mut current = leaf.to_string();
+    for step in path {
+        from_hex(&step.hash)?;
+        current = if step.left {
+            merkle_node(version, &step.hash, &current)
+        } else {
+            merkle_node(version, &current, &step.hash)
+        };
+    }
+    Ok(current)
+}
+
+/// The bytes the signature is computed over. Older versions signed the
+/// root alone.
+fn signed_payload(meta: &CommitMeta) -> Result<Vec<u8>> {
+    if meta.version < META_VERSION_HEADER {
+        return from_hex(&meta.root);
+    }
+    if meta.version >= META_VERSION_CANONICAL {
+        return from_hex(&commit_id(meta));
+    }
+
+    let mut out = format!("root {}\n", meta.root);
+    if let Some(parent) = meta.parents.first() {
+        out.push_str(&format!("parent {parent}\n"));
+    }
+    out.push_str(&format!("author {}\n", meta.author));
+    out.push_str(&format!("timestamp {}\n", meta.timestamp));
+    out.push('\n');
+    out.push_str(&meta.message);
+    Ok(out.into_bytes())
+}
+
+fn compute_meta(snapshot: &Snapshot, parents: Vec<String>, message: &str) -> Result<CommitMeta> {
+    let root = write_tree(META_VERSION_MODES, snapshot)?;
+    let (file_hashes, file_modes) = split_snapshot(snapshot);
+
+    let mut meta = CommitMeta {
+        version: META_VERSION_MODES,
+        root,
+        parents,
+        author: author_identity()?,
+        timestamp: now_secs(),
+        message: message.to_string(),
+        file_hashes,
+        file_modes,
+        public_key: String::new(),
+        signature: String::new(),
+    };
+
+    // sign the hash of the whole commit
+    let sk = load_or_generate_signing_key()?;
+    meta.public_key = to_hex(sk.verifying_key().as_bytes());
+    let sig = sk.sign(&signed_payload(&meta)?);
+    meta.signature = to_hex(&sig.to_bytes());
+
+    Ok(meta)
+}
+
+/// Check a commit's objects, Merkle root, ID, signature and signer. Returns
+/// the trusted signer's name, or a description of the first problem found.
+fn check_meta(id: &str, meta: &CommitMeta) -> Result<Result<String, String>> {
+    if meta.version > META_VERSION_MODES {
+        return Ok(Err(format!("Unsupported commit format version {}.", meta.version)));
+    }
+
+    if let Some(path) = meta.file_hashes.keys().find(|k| !valid_path_key(k)) {
+        return Ok(Err(format!("Invalid path {path:?}.")));
+    }
+
+    // Only executables and symlinks are listed, and only files in the commit
+    for (path, mode) in &meta.file_modes {
+        let known = mode == MODE_EXEC || mode == MODE_LINK;
+        if meta.version < META_VERSION_MODES || !known || !meta.file_hashes.contains_key(path) {
+            return Ok(Err(format!("Invalid mode {mode} for {path}.")));
+        }
+    }
+
+    // A content-derived ID must match the commit it names
+    if meta.version >= META_VERSION_CANONICAL && commit_id(meta) != id {
+        return Ok(Err("Commit ID does not match contents.".into()));
+    }
+
+    // Recompute file hashes from the stored objects
+    let recomputed = compute_hashes(&meta.file_hashes)?;
+
+    if recomputed != meta.file_hashes {
+        return Ok(Err("Hash mismatch detected.".into()));
+    }
+
+    // Recompute Merkle root
+    let new_root = match meta.version {
+        META_VERSION_FLAT => {
+            let values: Vec<String> = recomputed.values().cloned().collect();
+            merkle_root(meta.version, &values)
+                .ok_or_else(|| ScmError::Corrupt(format!("Commit {} lists no files", short_id(id))))?
+        }
+        _ => build_tree(meta.version, &nest_paths(&join_snapshot(&recomputed, &meta.file_modes))?, false)?,
+    };
+
+    if new_root != meta.root {
+        return Ok(Err("Merkle root mismatch.".into()));
+    }
+
+    // The stored tree objects must describe the same snapshot
+    if meta.version >= META_VERSION_TREE {
+        let mut walked = BTreeMap::new();
+        if walk_tree(meta.version, &meta.root, "", &mut walked).is_err() || walked != meta.snapshot() {
+            return Ok(Err("Tree objects do not match commit.".into()));
+        }
+    }
+
+    // Verify signature with the key recorded in the commit
+    let public_key = if meta.public_key.is_empty() {
+        local_public_key()?.unwrap_or_default()
+    } else {
+        meta.public_key.clone()
+    };
+    let Ok(vk) = parse_verifying_key(&public_key) else {
+        return Ok(Err("Malformed public key.".into()));
+    };
+
+    let payload = signed_payload(meta)?;
+
+    if !signature_valid(&vk, &payload, &meta.signature) {
+        if meta.public_key.is_empty() {
+            return Ok(Err("Signature verification failed (no key recorded, checked against local key).".into()));
+        }
+        return Ok(Err("Signature verification failed.".into()));
+    }
+
+    Ok(trusted_signer(&load_trusted_keys()?, &public_key))
+}
+
+fn signature_valid(vk: &VerifyingKey, payload: &[u8], signature: &str) -> bool {
+    let Ok(bytes) = from_hex(signature) else {
+        return false;
+    };
+    let Ok(arr) = <[u8; 64]>::try_from(bytes.as_slice()) else {
+        return false;
+    };
+    vk.verify(payload, &Signature::from_bytes(&arr)).is_ok()
+}
+
+/// Fail unless the commit passes check_meta. Returns the signer's name.
+fn verify_meta(id: &str, meta: &CommitMeta) -> Result<String> {
+    check_meta(id, meta)?.map_err(|problem| {
+        ScmError::Verification(format!("Commit {} failed verification: {problem}", short_id(id)))
+    })
+}
+
+// ---------------------------------------------------------
+// LINE DIFF
+// ---------------------------------------------------------
+//
+// Line-level LCS over the lines that differ once the common prefix and
+// suffix are trimmed, rendered as unified-diff hunks.
+
+const DIFF_CONTEXT: usize = 3;
+
+/// Above this many DP cells the changed middle is reported as one
+/// replacement rather than aligned line by line.
+const LCS_MAX_CELLS: usize = 25_000_000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Text and binary heuristics as git uses them: NUL bytes in the first 8000
+/// bytes, or anything that is not UTF-8.
+fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0) || std::str::from_utf8(data).is_err()
+}
+
+/// Lines including their terminators, so a missing final newline shows up.
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+fn diff_lines(a: &[&str], b: &[&str]) -> Vec<DiffOp> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let (am, bm) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let (m, n) = (am.len(), bm.len());
+
+    let mut ops: Vec<DiffOp> = (0..prefix).map(|i| DiffOp::Equal(i, i)).collect();
+
+    if (m + 1) * (n + 1) > LCS_MAX_CELLS {
+        ops.extend((0..m).map(|i| DiffOp::Delete(prefix + i)));
+        ops.extend((0..n).map(|j| DiffOp::Insert(prefix + j)));
+    } else {
+        // dp[i][j] = LCS length of am[i..] and bm[j..]
+        let mut dp = vec![vec![0u32; n + 1]; m + 1];
+        for i in (0..m).rev() {
+            for j in (0..n).rev() {
+                dp[i][j] = if am[i] == bm[j] {
+                    dp[i + 1][j + 1] + 1
+                } else {
+                    dp[i + 1][j].max(dp[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < m || j < n {
+            if i < m && j < n && am[i] == bm[j] {
+                ops.push(DiffOp::Equal(prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if i < m && (j == n || dp[i + 1][j] >= dp[i][j + 1]) {
+                ops.push(DiffOp::Delete(prefix + i));
+                i += 1;
+            } else {
+                ops.push(DiffOp::Insert(prefix + j));
+                j += 1;
+            }
+        }
+    }
+
+    let (sa, sb) = (a.len() - suffix, b.len() - suffix);
+    ops.extend((0..suffix).map(|k| DiffOp::Equal(sa + k, sb + k)));
+    ops
+}
+
+fn push_line(out: &mut String, marker: char, line: &str) {
+    out.push(marker);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+fn unified_diff(a: &[&str], b: &[&str], context: usize) -> String {
+    let ops = diff_lines(a, b);
+    let mut out = String::new();
+
+    let changes: Vec<usize> = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, DiffOp::Equal(..)))
+        .map(|(k, _)| k)
+        .collect();
+
+    let mut c = 0;
+    while c < changes.len() {
+        // Grow the hunk while the next change is within 2 * context lines
+        let start = changes[c].saturating_sub(context);
+        let mut last = changes[c];
+        while c + 1 < changes.len() && changes[c + 1] - last <= 2 * context {
+            c += 1;
+            last = changes[c];
+        }
+        let end = (last + context + 1).min(ops.len());
+        c += 1;
+
+        // Line numbers where the hunk starts on each side
+        let (mut a_start, mut b_start) = (0, 0);
+        for op in &ops[..start] {
+            match op {
+                DiffOp::Equal(..) => {
+                    a_start += 1;
+                    b_start += 1;
+                }
+                DiffOp::Delete(_) => a_start += 1,
+                DiffOp::Insert(_) => b_start += 1,
+            }
+        }
+
+        let hunk = &ops[start..end];
+        let a_len = hunk.iter().filter(|op| !matches!(op, DiffOp::Insert(_))).count();
+        let b_len = hunk.iter().filter(|op| !matches!(op, DiffOp::Delete(_))).count();
+
+        let range = |start: usize, len: usize| {
+            let first = if len == 0 { start } else { start + 1 };
+            if len == 1 { format!("{first}") } else { format!("{first},{len}") }
+        };
+        out.push_str(&format!("@@ -{} +{} @@\n", range(a_start, a_len), range(b_start, b_len)));
+
+        for op in hunk {
+            match *op {
+                DiffOp::Equal(i, _) => push_line(&mut out, ' ', a[i]),
+                DiffOp::Delete(i) => push_line(&mut out, '-', a[i]),
+                DiffOp::Insert(j) => push_line(&mut out, '+', b[j]),
+            }
+        }
+    }
+
+    out
+}
+
+/// One file that differs between two snapshots. Displays as a unified
+/// diff with a `diff --scm` header.
+#[derive(Clone, Debug)]
+pub struct FileDiff {
+    pub path: String,
+    /// Contents on the old side; None if the file was added.
+    pub old: Option<Vec<u8>>,
+    /// Contents on the new side; None if the file was deleted.
+    pub new: Option<Vec<u8>>,
+    /// Tree mode on each side ("100644", "100755" or "120000"), present
+    /// along with the contents.
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+}
+
+impl fmt::Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = &self.path;
+        writeln!(f, "diff --scm a/{path} b/{path}")?;
+        match (&self.old_mode, &self.new_mode) {
+            (None, Some(mode)) => writeln!(f, "new file mode {mode}")?,
+            (Some(mode), None) => writeln!(f, "deleted file mode {mode}")?,
+            (Some(old), Some(new)) if old != new => {
+                writeln!(f, "old mode {old}")?;
+                writeln!(f, "new mode {new}")?;
+            }
+            _ => {}
+        }
+        if self.old.is_some() && self.old == self.new {
+            return Ok(());
+        }
+
+        let old_bytes = self.old.as_deref().unwrap_or_default();
+        let new_bytes = self.new.as_deref().unwrap_or_default();
+        let old_name = self.old.as_ref().map_or("/dev/null".into(), |_| format!("a/{path}"));
+        let new_name = self.new.as_ref().map_or("/dev/null".into(), |_| format!("b/{path}"));
+
+        if is_binary(old_bytes) || is_binary(new_bytes) {
+            return writeln!(f, "Binary files {old_name} and {new_name} differ");
+        }
+
+        writeln!(f, "--- {old_name}")?;
+        writeln!(f, "+++ {new_name}")?;
+
+        let a = split_lines(std::str::from_utf8(old_bytes).unwrap_or_default());
+        let b = split_lines(std::str::from_utf8(new_bytes).unwrap_or_default());
+        write!(f, "{}", unified_diff(&a, &b, DIFF_CONTEXT))
+    }
+}
+
+/// Files that differ from commit `old_id` to commit `new_id`, or to the
+/// working tree if `new_id` is None, in path order.
+fn diff_commits(old_id: &str, new_id: Option<&str>) -> Result<Vec<FileDiff>> {
+    let old = load_meta(old_id)?.snapshot();
+    let new = match new_id {
+        Some(id) => load_meta(id)?.snapshot(),
+        None => working_snapshot()?,
+    };
+
+    let mut paths: Vec<&String> = old.keys().chain(new.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut out = Vec::new();
+    for path in paths {
+        let (a, b) = (old.get(path), new.get(path));
+        if a == b {
+            continue;
+        }
+
+        let old_data = match a {
+            Some(e) => Some(read_object(&e.hash)?),
+            None => None,
+        };
+        let new_data = match (b, new_id) {
+            (None, _) => None,
+            (Some(e), Some(_)) => Some(read_object(&e.hash)?),
+            (Some(_), None) => Some(read_working_file(path)?.0),
+        };
+
+        out.push(FileDiff {
+            path: path.clone(),
+            old: old_data,
+            new: new_data,
+            old_mode: a.map(|e| e.mode.clone()),
+            new_mode: b.map(|e| e.mode.clone()),
+        });
+    }
+
+    Ok(out)
+}
+
+// ---------------------------------------------------------
+// THREE-WAY MERGE
+// ---------------------------------------------------------
+//
+// diff3-style: align ours and theirs against the base with the line LCS,
+// copy lines all three agree on, and resolve each differing stretch by
+// taking whichever side changed it. Stretches both sides changed
+// differently become conflict blocks.
+
+fn merge_lines(
+    base: &[&str],
+    ours: &[&str],
+    theirs: &[&str],
+    ours_label: &str,
+    theirs_label: &str,
+) -> (String, bool) {
+    // For each base line, the matching line on each side (if any)
+    let matches = |side: &[&str]| {
+        let mut m = vec![None; base.len()];
+        for op in diff_lines(base, side) {
+            if let DiffOp::Equal(i, j) = op {
+                m[i] = Some(j);
+            }
+        }
+        m
+    };
+    let mo = matches(ours);
+    let mt = matches(theirs);
+
+    let mut out = String::new();
+    let mut conflict = false;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+
+    loop {
+        // Next base line both sides still share
+        let stable = (i..base.len()).find(|&x| mo[x].is_some() && mt[x].is_some());
+        let (bi, oj, tk) = match stable {
+            Some(x) => (x, mo[x].unwrap(), mt[x].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+
+        let (b, o, t) = (&base[i..bi], &ours[j..oj], &theirs[k..tk]);
+        if o == b {
+            out.extend(t.iter().copied());
+        } else if t == b || o == t {
+            out.extend(o.iter().copied());
+        } else {
+            conflict = true;
+            let block = |out: &mut String, lines: &[&str]| {
+                for line in lines {
+                    out.push_str(line);
+                }
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+            };
+            out.push_str(&format!("<<<<<<< {ours_label}\n"));
+            block(&mut out, o);
+            out.push_str("=======\n");
+            block(&mut out, t);
+            out.push_str(&format!(">>>>>>> {theirs_label}\n"));
+        }
+
+        match stable {
+            Some(_) => {
+                out.push_str(base[bi]);
+                i = bi + 1;
+                j = oj + 1;
+                k = tk + 1;
+            }
+            None => break,
+        }
+    }
+
+    (out, conflict)
+}
+
+/// A path a merge or revert could not combine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub path: String,
+    pub kind: ConflictKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the same lines; the working file holds conflict
+    /// markers.
+    Content,
+    /// Both sides changed a binary file or symlink; ours is kept.
+    Binary,
+    /// One side changed the file and the other deleted it; the surviving
+    /// version is kept.
+    ModifyDelete,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ConflictKind::Content => write!(f, "CONFLICT (content): Merge conflict in {}", self.path),
+            ConflictKind::Binary => write!(f, "CONFLICT (binary): {}", self.path),
+            ConflictKind::ModifyDelete => write!(f, "CONFLICT (modify/delete): {}", self.path),
+        }
+    }
+}
+
+/// Three-way merge of whole snapshots into the working tree, refusing
+/// before any write if that would overwrite an untracked file or local
+/// change. Returns the snapshot to stage (conflicted paths keep our
+/// version) and the conflicts.
+fn merge_snapshots(b: &Snapshot, o: &Snapshot, t: &Snapshot, theirs_label: &str) -> Result<(Snapshot, Vec<Conflict>)> {
+    let mut paths: Vec<&String> = b.keys().chain(o.keys()).chain(t.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    // Every path the merge replaces or removes is checked before any is
+    // written; `o` is HEAD. Conflicted paths have no known result yet.
+    let index = load_index()?;
+    let touched: Vec<(&String, Option<&Entry>)> = paths
+        .iter()
+        .filter_map(|&path| {
+            let (be, oe, te) = (b.get(path), o.get(path), t.get(path));
+            if oe == te || be == te {
+                None
+            } else if be == oe {
+                Some((path, te))
+            } else {
+                Some((path, None))
+            }
+        })
+        .collect();
+    let removing: BTreeSet<&str> = touched
+        .iter()
+        .filter(|(path, te)| te.is_none() && b.get(*path) == o.get(*path))
+        .map(|(path, _)| path.as_str())
+        .collect();
+    check_overwrites(touched.iter().copied(), o, &index, &removing)?;
+
+    let mut merged = BTreeMap::new();
+    let mut conflicts = Vec::new();
+
+    for path in paths {
+        let (be, oe, te) = (b.get(path), o.get(path), t.get(path));
+
+        // One side unchanged (or both changed the same way): take the other
+        let clean = if oe == te || be == te {
+            Some(oe)
+        } else if be == oe {
+            Some(te)
+        } else {
+            None
+        };
+
+        if let Some(entry) = clean {
+            match entry {
+                Some(e) => {
+                    if Some(e) != oe {
+                        write_working_file(path, &read_object(&e.hash)?, &e.mode)?;
+                    }
+                    merged.insert(path.clone(), e.clone());
+                }
+                None => remove_working_file(path)?,
+            }
+            continue;
+        }
+
+        // Both sides changed the file differently
+        let read = |e: Option<&Entry>| -> Result<Vec<u8>> {
+            e.map_or(Ok(Vec::new()), |e| read_object(&e.hash))
+        };
+        let (bd, od, td) = (read(be)?, read(oe)?, read(te)?);
+        let is_link = |e: Option<&Entry>| e.is_some_and(|e| e.mode == MODE_LINK);
+
+        let (Some(ours), Some(theirs)) = (oe, te) else {
+            // Keep whichever version still exists, ours preferred
+            let keep = oe.or(te).unwrap();
+            write_working_file(path, &read(Some(keep))?, &keep.mode)?;
+            if let Some(e) = oe {
+                merged.insert(path.clone(), e.clone());
+            }
+            conflicts.push(Conflict { path: path.clone(), kind: ConflictKind::ModifyDelete });
+            continue;
+        };
+
+        // Symlink targets are merged like binaries: all or nothing
+        if [be, oe, te].into_iter().any(is_link) || is_binary(&bd) || is_binary(&od) || is_binary(&td) {
+            write_working_file(path, &od, &ours.mode)?;
+            merged.insert(path.clone(), ours.clone());
+            conflicts.push(Conflict { path: path.clone(), kind: ConflictKind::Binary });
+            continue;
+        }
+
+        // The mode follows whichever side changed it, ours preferred
+        let mode = if be.is_some_and(|e| e.mode == ours.mode) { &theirs.mode } else { &ours.mode };
+
+        let split = |d: &[u8]| std::str::from_utf8(d).unwrap().to_string();
+        let (bs, os, ts) = (split(&bd), split(&od), split(&td));
+        let (text, conflict) =
+            merge_lines(&split_lines(&bs), &split_lines(&os), &split_lines(&ts), "HEAD", theirs_label);
+
+        write_working_file(path, text.as_bytes(), mode)?;
+        if conflict {
+            merged.insert(path.clone(), ours.clone());
+            conflicts.push(Conflict { path: path.clone(), kind: ConflictKind::Content });
+        } else {
+            let entry = Entry { hash: write_object(text.as_bytes())?, mode: mode.clone() };
+            merged.insert(path.clone(), entry);
+        }
+    }
+
+    Ok((merged, conflicts))
+}
+
+/// Every ancestor of `id`, including itself.
+fn ancestors(id: &str) -> Result<Vec<String>> {
+    Ok(history(&[id.to_string()])?.into_iter().map(|(id, _)| id).collect())
+}
+
+/// Newest commit reachable from both `a` and `b`.
+fn merge_base(a: &str, b: &str) -> Result<Option<String>> {
+    let from_a = ancestors(a)?;
+    Ok(history(&[b.to_string()])?
+        .into_iter()
+        .map(|(id, _)| id)
+        .find(|id| from_a.contains(id)))
+}
+
+fn load_conflicts() -> Result<Vec<String>> {
+    if !merge_conflicts_path().exists() {
+        return Ok(Vec::new());
+    }
+    Ok(fs::read_to_string(merge_conflicts_path())?
+        .lines()
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// Mark conflicted paths under `key` as resolved.
+fn resolve_conflicts(key: &str) -> Result<()> {
+    let conflicts = load_conflicts()?;
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let left: Vec<String> = conflicts.into_iter().filter(|p| !path_covers(key, p)).collect();
+    write_atomic(&merge_conflicts_path(), left.iter().map(|p| format!("{p}\n")).collect::<String>())
+}
+
+/// Throw away a conflicted merge or revert and return to HEAD.
+fn abort_in_progress() -> Result<()> {
+    if !merge_msg_path().exists() {
+        return Err(ScmError::Refused("No merge or revert in progress.".into()));
+    }
+    if let Some(head) = head_commit()? {
+        let meta = load_meta(&head)?;
+        let options = RestoreOptions { keep: false, force: true };
+        checkout_snapshot(&meta.snapshot(), &[String::new()], options)?;
+        save_index(&meta.snapshot())?;
+    }
+    clear_merge_state()?;
+    Ok(())
+}
+
+fn clear_merge_state() -> Result<()> {
+    for path in [merge_head_path(), merge_msg_path(), merge_conflicts_path()] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// True if the index or any tracked working file differs from HEAD.
+fn has_uncommitted_changes() -> Result<bool> {
+    let head = head_snapshot()?;
+    let index = load_index()?;
+    if index != head {
+        return Ok(true);
+    }
+
+    for (path, entry) in &index {
+        if !working_file_exists(path) || working_entry(path)? != *entry {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// How a merge ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MergeOutcome {
+    /// HEAD already contains the other commit.
+    UpToDate,
+    /// HEAD moved forward to the other commit.
+    FastForward(ObjectId),
+    /// A merge commit was made.
+    Merged(ObjectId),
+    /// The merge stopped with conflicts in the working tree. Add the fixed
+    /// files and commit to conclude it, or abort it.
+    Conflicts(Vec<Conflict>),
+}
+
+/// How a revert ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RevertOutcome {
+    /// HEAD does not contain the commit's changes.
+    NothingToRevert,
+    /// The revert was committed.
+    Reverted(ObjectId),
+    /// The revert stopped with conflicts, as a merge does.
+    Conflicts(Vec<Conflict>),
+}
+
+/// Stage what merged cleanly and leave the conflicts for the user to fix
+/// and commit with `message`.
+fn stop_for_conflicts(merged: &Snapshot, message: &str, conflicts: &[Conflict]) -> Result<()> {
+    save_index(merged)?;
+    write_atomic(&merge_msg_path(), message)?;
+    write_atomic(&merge_conflicts_path(), conflicts.iter().map(|c| format!("{}\n", c.path)).collect::<String>())
+}
+
+/// Merge `spec` into HEAD once every commit involved has been verified,
+/// fast-forwarding when HEAD is its ancestor unless `no_ff`.
+fn merge_rev(spec: &str, no_ff: bool) -> Result<MergeOutcome> {
+    if merge_msg_path().exists() {
+        return Err(ScmError::Refused("A merge or revert is already in progress; commit it or run `scm merge --abort`.".into()));
+    }
+    if has_uncommitted_changes()? {
+        return Err(ScmError::Refused("Commit or discard your changes before merging.".into()));
+    }
+
+    let Some(ours) = head_commit()? else {
+        return Err(ScmError::NotFound("No commits yet.".into()));
+    };
+    let theirs = resolve_rev(spec)?;
+
+    let their_meta = load_meta(&theirs)?;
+    verify_meta(&theirs, &their_meta)?;
+
+    let Some(base) = merge_base(&ours, &theirs)? else {
+        return Err(ScmError::Refused(format!("No common ancestor between HEAD and {spec}.")));
+    };
+
+    if base == theirs {
+        return Ok(MergeOutcome::UpToDate);
+    }
+
+    if base == ours && !no_ff {
+        restore_from(&their_meta)?;
+        save_index(&their_meta.snapshot())?;
+        update_head(&theirs)?;
+        return Ok(MergeOutcome::FastForward(ObjectId(theirs)));
+    }
+
+    let base_meta = load_meta(&base)?;
+    verify_meta(&base, &base_meta)?;
+
+    let b = &base_meta.snapshot();
+    let o = load_meta(&ours)?.snapshot();
+    let t = &their_meta.snapshot();
+
+    let (merged, conflicts) = merge_snapshots(b, &o, t, spec)?;
+
+    let into = match read_head()? {
+        Head::Branch(name) => name,
+        Head::Detached(id) => id.short().to_string(),
+    };
+    let message = format!("Merge {spec} into {into}");
+
+    if conflicts.is_empty() {
+        let id = create_commit(merged, vec![ours, theirs], &message)?;
+        return Ok(MergeOutcome::Merged(ObjectId(id)));
+    }
+
+    write_atomic(&merge_head_path(), format!("{theirs}\n"))?;
+    stop_for_conflicts(&merged, &message, &conflicts)?;
+    Ok(MergeOutcome::Conflicts(conflicts))
+}
+
+/// Undo what commit `rev` changed relative to its first parent on top of
+/// HEAD, once both have been verified.
+fn revert_commit(rev: &str) -> Result<RevertOutcome> {
+    if merge_msg_path().exists() {
+        return Err(ScmError::Refused("A merge or revert is already in progress; commit it or run `scm revert --abort`.".into()));
+    }
+    if has_uncommitted_changes()? {
+        return Err(ScmError::Refused("Commit or discard your changes before reverting.".into()));
+    }
+
+    let Some(head) = head_commit()? else {
+        return Err(ScmError::NotFound("No commits yet.".into()));
+    };
+    let target = resolve_rev(rev)?;
+    let meta = load_meta(&target)?;
+
+    verify_meta(&target, &meta)?;
+
+    // Undo target's changes relative to its (first) parent
+    let parent_files = match commit_parents(&target, &meta)?.first() {
+        Some(parent) => {
+            let parent_meta = load_meta(parent)?;
+            verify_meta(parent, &parent_meta)?;
+            parent_meta.snapshot()
+        }
+        None => BTreeMap::new(),
+    };
+
+    let ours = load_meta(&head)?.snapshot();
+    let label = format!("parent of {}", short_id(&target));
+    let (merged, conflicts) = merge_snapshots(&meta.snapshot(), &ours, &parent_files, &label)?;
+
+    let subject = meta.message.lines().next().unwrap_or("");
+    let message = format!("Revert \"{subject}\"\n\nThis reverts commit {target}.");
+
+    if !conflicts.is_empty() {
+        stop_for_conflicts(&merged, &message, &conflicts)?;
+        return Ok(RevertOutcome::Conflicts(conflicts));
+    }
+    if merged == ours {
+        return Ok(RevertOutcome::NothingToRevert);
+    }
+
+    let id = create_commit(merged, vec![head], &message)?;
+    Ok(RevertOutcome::Reverted(ObjectId(id)))
+}
+
+// ---------------------------------------------------------
+// INCLUSION PROOFS
+// ---------------------------------------------------------
+//
+// A proof ties one file to a signed commit: for each directory from the
+// file up to the root it lists the entry's name and mode plus the Merkle
+// siblings needed to rebuild that directory's hash. The commit header
+// (everything but file_hashes) lets the verifier recompute the commit ID
+// and check the signature without any other part of the snapshot.
+
+#[derive(Serialize, Deserialize)]
+struct ProofStep {
+    hash: String,
+    /// The sibling sits to the left of the running hash.
+    left: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProofLevel {
+    name: String,
+    mode: String,
+    siblings: Vec<ProofStep>,
+}
+
+/// Evidence that a file is part of a signed commit, checkable without the
+/// repository. It is stored and exchanged as JSON.
+#[derive(Serialize, Deserialize)]
+pub struct InclusionProof {
+    commit: String,
+    path: String,
+    file_hash: String,
+    /// Innermost directory first.
+    levels: Vec<ProofLevel>,
+    header: CommitMeta,
+}
+
+impl InclusionProof {
+    /// The commit the file is part of.
+    pub fn commit(&self) -> &str {
+        &self.commit
+    }
+
+    /// The file's path in the commit.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// The leaf a directory entry contributes to its tree's Merkle root.
+fn proof_leaf(version: u32, mode: &str, hash: &str, name: &str) -> String {
+    if version == META_VERSION_FLAT {
+        return hash.to_string();
+    }
+    entry_leaf(version, &TreeEntry { mode: mode.into(), hash: hash.into(), name: name.into() })
+}
+
+fn build_proof(id: &str, meta: &CommitMeta, path: &str) -> Result<InclusionProof> {
+    let not_found = || ScmError::NotFound(format!("{path} is not in commit {}", short_id(id)));
+    let file_hash = meta.file_hashes.get(path).ok_or_else(not_found)?.clone();
+
+    let mut levels = Vec::new();
+
+    if meta.version == META_VERSION_FLAT {
+        let values: Vec<String> = meta.file_hashes.values().cloned().collect();
+        let index = meta.file_hashes.keys().position(|k| k == path).unwrap();
+        levels.push(ProofLevel { name: path.into(), mode: String::new(), siblings: merkle_path(meta.version, &values, index) });
+    } else {
+        let mut tree = meta.root.clone();
+        for name in path.split('/') {
+            let entries = read_tree(meta.version, &tree)?;
+            let index = entries.iter().position(|e| e.name == name).ok_or_else(not_found)?;
+            let leaves: Vec<String> = entries.iter().map(|e| entry_leaf(meta.version, e)).collect();
+            levels.push(ProofLevel {
+                name: name.into(),
+                mode: entries[index].mode.clone(),
+                siblings: merkle_path(meta.version, &leaves, index),
+            });
+            tree = entries[index].hash.clone();
+        }
+        levels.reverse();
+    }
+
+    let header = CommitMeta {
+        version: meta.version,
+        root: meta.root.clone(),
+        parents: meta.parents.clone(),
+        author: meta.author.clone(),
+        timestamp: meta.timestamp,
+        message: meta.message.clone(),
+        file_hashes: BTreeMap::new(),
+        file_modes: BTreeMap::new(),
+        public_key: meta.public_key.clone(),
+        signature: meta.signature.clone(),
+    };
+
+    Ok(InclusionProof { commit: id.into(), path: path.into(), file_hash, levels, header })
+}
+
+/// Check a proof against a file's contents, accepting signatures by
+/// `keys`. Returns the signer's name.
+fn check_proof(proof: &InclusionProof, data: &[u8], keys: &[TrustedKey]) -> Result<Result<String, String>> {
+    let header = &proof.header;
+
+    if to_hex(&Sha256::digest(data)) != proof.file_hash {
+        return Ok(Err("File contents do not match the proof.".into()));
+    }
+
+    let names: Vec<&str> = proof.levels.iter().rev().map(|l| l.name.as_str()).collect();
+    if names.join("/") != proof.path {
+        return Ok(Err("Proof levels do not spell out the path.".into()));
+    }
+
+    // The innermost entry must be a file and every one above it a
+    // directory, or a tree could be passed off as a file's contents
+    if header.version != META_VERSION_FLAT {
+        let Some((file, dirs)) = proof.levels.split_first() else {
+            return Ok(Err("Proof has no levels.".into()));
+        };
+        if ![MODE_FILE, MODE_EXEC, MODE_LINK].contains(&file.mode.as_str()) {
+            return Ok(Err(format!("{} is not a file in the proof.", proof.path)));
+        }
+        if dirs.iter().any(|l| l.mode != MODE_TREE) {
+            return Ok(Err("Proof levels above the file are not all directories.".into()));
+        }
+    }
+
+    let mut current = proof.file_hash.clone();
+    for level in &proof.levels {
+        let leaf = proof_leaf(header.version, &level.mode, &current, &level.name);
+        current = merkle_fold(header.version, &leaf, &level.siblings)?;
+    }
+    if current != header.root {
+        return Ok(Err("Merkle path does not lead to the commit root.".into()));
+    }
+
+    if header.version >= META_VERSION_CANONICAL && commit_id(header) != proof.commit {
+        return Ok(Err("Commit header does not match the commit ID.".into()));
+    }
+
+    let public_key = if header.public_key.is_empty() {
+        local_public_key()?.unwrap_or_default()
+    } else {
+        header.public_key.clone()
+    };
+    let Ok(vk) = parse_verifying_key(&public_key) else {
+        return Ok(Err("Malformed public key.".into()));
+    };
+    if !signature_valid(&vk, &signed_payload(header)?, &header.signature) {
+        return Ok(Err("Signature verification failed.".into()));
+    }
+
+    Ok(trusted_signer(keys, &public_key))
+}
+
+/// Check an inclusion proof against a file's contents outside any
+/// repository, trusting only the local user's key. Returns the signer.
+pub fn verify_proof(proof: &InclusionProof, data: &[u8]) -> Result<String> {
+    check_proof(proof, data, &local_trust()?)?.map_err(ScmError::Verification)
+}
+
+// ---------------------------------------------------------
+// TRANSFER
+// ---------------------------------------------------------
+//
+// clone, push and pull copy commits between two .scm directories on the
+// local filesystem. The receiving side copies the blobs each commit needs,
+// rebuilds its tree objects and runs the same checks as fsck before any
+// commit metadata is written, so nothing unverified becomes reachable.
+// Push runs the receiving side with the destination as its context, so its own
+// trust list decides what it accepts.
+
+/// The .scm directory of a repository, given its working copy or the
+/// .scm directory itself.
+fn remote_scm_dir(path: &Path) -> Result<PathBuf> {
+    let dir = if path.file_name() == Some(".scm".as_ref()) { path.to_path_buf() } else { path.join(".scm") };
+
+    if !dir.join("commits").is_dir() {
+        return Err(ScmError::NotFound(format!("{} is not an scm repository", path.display())));
+    }
+    Ok(fs::canonicalize(dir)?)
+}
+
+fn remote_refs(dir: &Path) -> Result<Vec<(String, String)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut refs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if valid_ref_name(&name) {
+            refs.push((name.clone(), fs::read_to_string(dir.join(&name))?.trim().to_string()));
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+fn is_hash_like(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Copy `tip` and every ancestor missing here from the repository at
+/// `remote`. Returns how many commits arrived, or why one was rejected.
+fn fetch_commits(remote: &Path, tip: &str) -> Result<Result<usize, String>> {
+    let mut pending = vec![tip.to_string()];
+    let mut incoming: Vec<(String, String, CommitMeta)> = Vec::new();
+
+    while let Some(id) = pending.pop() {
+        if commits_dir().join(&id).join("meta.json").exists() || incoming.iter().any(|(i, _, _)| *i == id) {
+            continue;
+        }
+        if !is_hash_like(&id) {
+            return Ok(Err(format!("Invalid commit ID {id}")));
+        }
+
+        let raw = fs::read_to_string(remote.join("commits").join(&id).join("meta.json"))
+            .map_err(|e| io::Error::new(e.kind(), format!("Missing commit {id} in source: {e}")))?;
+        let Ok(meta) = serde_json::from_str::<CommitMeta>(&raw) else {
+            return Ok(Err(format!("Malformed metadata for commit {}", short_id(&id))));
+        };
+
+        pending.extend(meta.parents.iter().cloned());
+        incoming.push((id, raw, meta));
+    }
+
+    import_commits(&incoming, |hash| {
+        read_object_in(&remote.join("objects"), hash)
+    })
+}
+
+/// Verify and store commits received from elsewhere, reading the blobs
+/// they need from `source`. No commit becomes visible unless all of them
+/// pass.
+fn import_commits(
+    incoming: &[(String, String, CommitMeta)],
+    source: impl Fn(&str) -> Result<Vec<u8>>,
+) -> Result<Result<usize, String>> {
+    for (id, _, meta) in incoming {
+        // Checked again by check_meta, but before any tree is built from them
+        if let Some(path) = meta.file_hashes.keys().find(|k| !valid_path_key(k)) {
+            return Ok(Err(format!("Commit {} has an invalid path {path:?}", short_id(id))));
+        }
+        for hash in meta.file_hashes.values() {
+            if !is_hash_like(hash) {
+                return Ok(Err(format!("Commit {} names invalid object {hash}", short_id(id))));
+            }
+            if object_path(hash).exists() {
+                continue;
+            }
+            if write_object(&source(hash)?)? != *hash {
+                return Ok(Err(format!("Object {hash} is corrupt in the source")));
+            }
+        }
+
+        if meta.version >= META_VERSION_TREE {
+            write_tree(meta.version, &meta.snapshot())?;
+        }
+
+        if let Err(problem) = check_meta(id, meta)? {
+            return Ok(Err(format!("Commit {}: {problem}", short_id(id))));
+        }
+    }
+
+    // Everything checked out; make the commits visible
+    for (id, raw, _) in incoming {
+        store_commit(id, raw)?;
+    }
+
+    Ok(Ok(incoming.len()))
+}
+
+/// How a push or pull left the receiving branch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferOutcome {
+    /// The branch already held the sent history.
+    UpToDate,
+    /// The branch moved to `tip`; `received` commits were new.
+    Updated { branch: String, tip: ObjectId, received: usize },
+}
+
+enum RefUpdate {
+    UpToDate,
+    FastForward,
+    Diverged,
+}
+
+/// How moving a ref from `old` to `new` relates to history. Both commits
+/// must already be present.
+fn classify_update(old: Option<&str>, new: &str) -> Result<RefUpdate> {
+    let Some(old) = old else {
+        return Ok(RefUpdate::FastForward);
+    };
+    if old == new || ancestors(old)?.iter().any(|id| id == new) {
+        return Ok(RefUpdate::UpToDate);
+    }
+    if ancestors(new)?.iter().any(|id| id == old) {
+        return Ok(RefUpdate::FastForward);
+    }
+    Ok(RefUpdate::Diverged)
+}
+
+/// Fill the new repository in the context from the one at `remote`: its
+/// trusted keys plus the local user's own, every branch and tag, and its
+/// HEAD, checked out.
+fn clone_into(remote: &Path) -> Result<()> {
+    if remote.join("trusted_keys").exists() {
+        fs::copy(remote.join("trusted_keys"), trusted_keys_path())?;
+    }
+    ensure_repo()?;
+
+    if let Some(public_key) = local_public_key()? {
+        let mut keys = load_trusted_keys()?;
+        if !keys.iter().any(|k| k.public_key == public_key) {
+            keys.push(TrustedKey { name: author_identity()?, public_key, revoked: false });
+            save_trusted_keys(&keys)?;
+        }
+    }
+
+    for (branch, tip) in remote_refs(&remote.join("refs").join("heads"))? {
+        if let Err(problem) = fetch_commits(remote, &tip)? {
+            return Err(ScmError::Verification(format!("Rejected: {problem}")));
+        }
+        set_branch(&branch, &tip)?;
+    }
+
+    for (name, target) in remote_refs(&remote.join("refs").join("tags"))? {
+        if !is_hash_like(&target) {
+            return Err(ScmError::Verification(format!("Rejected: tag {name} has an invalid target")));
+        }
+
+        // Lightweight tags name a commit; annotated ones a tag object
+        let commit = if remote.join("commits").join(&target).join("meta.json").exists() {
+            target.clone()
+        } else {
+            let data = read_object_in(&remote.join("objects"), &target)?;
+            if to_hex(&Sha256::digest(&data)) != target {
+                return Err(ScmError::Verification(format!("Rejected: tag object for {name} is corrupt")));
+            }
+            let Ok(tag) = serde_json::from_slice::<TagObject>(&data) else {
+                return Err(ScmError::Verification(format!("Rejected: tag object for {name} is malformed")));
+            };
+            if let Err(problem) = check_tag(&tag, &name)? {
+                return Err(ScmError::Verification(format!("Rejected: tag {name}: {problem}")));
+            }
+            write_object(&data)?;
+            tag.target
+        };
+
+        if let Err(problem) = fetch_commits(remote, &commit)? {
+            return Err(ScmError::Verification(format!("Rejected: {problem}")));
+        }
+        fs::create_dir_all(tags_dir())?;
+        write_atomic(&tags_dir().join(&name), format!("{target}\n"))?;
+    }
+
+    let head = fs::read_to_string(remote.join("HEAD")).unwrap_or_default();
+    let head = head.trim();
+    match head.strip_prefix("ref: refs/heads/") {
+        Some(branch) if valid_ref_name(branch) => write_head(&Head::Branch(branch.to_string()))?,
+        _ if is_hash_like(head) => {
+            if let Err(problem) = fetch_commits(remote, head)? {
+                return Err(ScmError::Verification(format!("Rejected: {problem}")));
+            }
+            write_head(&Head::Detached(ObjectId(head.to_string())))?;
+        }
+        _ => {}
+    }
+
+    if let Some(id) = head_commit()? {
+        let meta = load_meta(&id)?;
+        restore_from(&meta)?;
+        save_index(&meta.snapshot())?;
+    }
+    Ok(())
+}
+
+/// Send the checked-out branch to the repository at `path`.
+fn push_to(path: &Path, force: bool) -> Result<TransferOutcome> {
+    let Head::Branch(branch) = read_head()? else {
+        return Err(ScmError::Refused("HEAD is detached; check out a branch to push.".into()));
+    };
+    let Some(tip) = branch_tip(&branch)? else {
+        return Err(ScmError::NotFound("No commits yet.".into()));
+    };
+
+    let source = fs::canonicalize(scm_dir())?;
+    let remote = remote_scm_dir(path)?;
+    if remote == source {
+        return Err(ScmError::Refused("Cannot push a repository into itself.".into()));
+    }
+
+    with_root(remote.parent().unwrap(), || receive_push(&source, &branch, &tip, force))
+}
+
+/// The receiving half of push, run inside the destination repository.
+fn receive_push(source: &Path, branch: &str, tip: &str, force: bool) -> Result<TransferOutcome> {
+    let _lock = open_repo()?;
+
+    let checked_out = matches!(read_head()?, Head::Branch(ref b) if b == branch);
+    if checked_out && (merge_msg_path().exists() || has_uncommitted_changes()?) {
+        return Err(ScmError::Refused(format!("Branch {branch} is checked out in the destination and has uncommitted changes.")));
+    }
+
+    let received = match fetch_commits(source, tip)? {
+        Ok(n) => n,
+        Err(problem) => {
+            return Err(ScmError::Verification(format!("Rejected: {problem}")));
+        }
+    };
+
+    match classify_update(branch_tip(branch)?.as_deref(), tip)? {
+        RefUpdate::UpToDate => return Ok(TransferOutcome::UpToDate),
+        RefUpdate::Diverged if !force => {
+            return Err(ScmError::Refused(format!("Refusing non-fast-forward update of {branch}; pull first or use --force.")));
+        }
+        _ => {}
+    }
+
+    // The working tree is updated against the old tip, before the ref moves
+    if checked_out {
+        let meta = load_meta(tip)?;
+        restore_from(&meta)?;
+        save_index(&meta.snapshot())?;
+    }
+    set_branch(branch, tip)?;
+
+    Ok(TransferOutcome::Updated { branch: branch.to_string(), tip: ObjectId(tip.to_string()), received })
+}
+
+/// Bring the checked-out branch up to date with the branch of the same
+/// name in the repository at `path`.
+fn pull_from(path: &Path, force: bool) -> Result<TransferOutcome> {
+    let Head::Branch(branch) = read_head()? else {
+        return Err(ScmError::Refused("HEAD is detached; check out a branch to pull into.".into()));
+    };
+
+    let remote = remote_scm_dir(path)?;
+    let Some(theirs) = remote_refs(&remote.join("refs").join("heads"))?
+        .into_iter()
+        .find(|(name, _)| *name == branch)
+        .map(|(_, tip)| tip)
+    else {
+        return Err(ScmError::NotFound(format!("{} has no branch {branch}.", path.display())));
+    };
+
+    if merge_msg_path().exists() || has_uncommitted_changes()? {
+        return Err(ScmError::Refused("Commit or discard your changes before pulling.".into()));
+    }
+
+    let received = match fetch_commits(&remote, &theirs)? {
+        Ok(n) => n,
+        Err(problem) => {
+            return Err(ScmError::Verification(format!("Rejected: {problem}")));
+        }
+    };
+
+    match classify_update(branch_tip(&branch)?.as_deref(), &theirs)? {
+        RefUpdate::UpToDate => return Ok(TransferOutcome::UpToDate),
+        RefUpdate::Diverged if !force => {
+            return Err(ScmError::Refused(format!(
+                "Refusing non-fast-forward update of {branch}; run `scm merge {}` or use --force.",
+                short_id(&theirs)
+            )));
+        }
+        _ => {}
+    }
+
+    let meta = load_meta(&theirs)?;
+    restore_from(&meta)?;
+    save_index(&meta.snapshot())?;
+    set_branch(&branch, &theirs)?;
+
+    Ok(TransferOutcome::Updated { branch, tip: ObjectId(theirs), received })
+}
+
+// ---------------------------------------------------------
+// BUNDLES
+// ---------------------------------------------------------
+//
+// A bundle carries history to a repository that cannot be reached
+// directly. It is a header line, one "ref <name> <id>" line per tip, then
+// "commit <id> <len>" and "object <hash> <len>" records each followed by
+// that many bytes and a newline, and finally "checksum <sha256>" over
+// everything before it. Commits are imported through the same checks as
+// pull.
+
+const BUNDLE_HEADER: &str = "scm-bundle 1\n";
+
+struct Bundle {
+    refs: Vec<(String, String)>,
+    commits: Vec<(String, String, CommitMeta)>,
+    objects: BTreeMap<String, Vec<u8>>,
+}
+
+fn write_bundle(bundle: &Bundle) -> Vec<u8> {
+    let mut out = BUNDLE_HEADER.as_bytes().to_vec();
+
+    for (name, id) in &bundle.refs {
+        out.extend(format!("ref {name} {id}\n").as_bytes());
+    }
+    for (id, raw, _) in &bundle.commits {
+        out.extend(format!("commit {id} {}\n", raw.len()).as_bytes());
+        out.extend(raw.as_bytes());
+        out.push(b'\n');
+    }
+    for (hash, data) in &bundle.objects {
+        out.extend(format!("object {hash} {}\n", data.len()).as_bytes());
+        out.extend(data);
+        out.push(b'\n');
+    }
+
+    let checksum = to_hex(&Sha256::digest(&out));
+    out.extend(format!("checksum {checksum}\n").as_bytes());
+    out
+}
+
+fn read_bundle(data: &[u8]) -> Result<Bundle> {
+    let bad = |msg: &str| ScmError::Corrupt(format!("Malformed bundle: {msg}"));
+
+    // The checksum line comes last and covers everything before it
+    let body_end = data[..data.len().saturating_sub(1)]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let trailer = std::str::from_utf8(&data[body_end..]).map_err(|_| bad("unreadable checksum"))?;
+    let Some(checksum) = trailer.strip_prefix("checksum ").and_then(|s| s.strip_suffix('\n')) else {
+        return Err(bad("missing checksum"));
+    };
+    let body = &data[..body_end];
+    if to_hex(&Sha256::digest(body)) != checksum {
+        return Err(ScmError::Corrupt("Bundle checksum mismatch; the file is damaged".into()));
+    }
+    if !body.starts_with(BUNDLE_HEADER.as_bytes()) {
+        return Err(bad("not an scm bundle"));
+    }
+
+    let mut bundle = Bundle { refs: Vec::new(), commits: Vec::new(), objects: BTreeMap::new() };
+    let mut pos = BUNDLE_HEADER.len();
+
+    while pos < body.len() {
+        let nl = pos + body[pos..].iter().position(|&b| b == b'\n').ok_or_else(|| bad("truncated record"))?;
+        let line = std::str::from_utf8(&body[pos..nl]).map_err(|_| bad("unreadable record"))?;
+        pos = nl + 1;
+
+        match line.split(' ').collect::<Vec<_>>().as_slice() {
+            ["ref", name, id] => bundle.refs.push((name.to_string(), id.to_string())),
+            [kind @ ("commit" | "object"), id, len] => {
+                let len: usize = len.parse().map_err(|_| bad("bad length"))?;
+                let end = pos
+                    .checked_add(len)
+                    .filter(|&end| end < body.len() && body[end] == b'\n')
+                    .ok_or_else(|| bad("truncated record"))?;
+                let payload = body[pos..end].to_vec();
+                pos = end + 1;
+
+                if *kind == "commit" {
+                    let raw = String::from_utf8(payload).map_err(|_| bad("commit is not UTF-8"))?;
+                    let meta = serde_json::from_str(&raw).map_err(|_| bad("malformed commit"))?;
+                    bundle.commits.push((id.to_string(), raw, meta));
+                } else {
+                    bundle.objects.insert(id.to_string(), payload);
+                }
+            }
+            _ => return Err(bad("unknown record")),
+        }
+    }
+
+    Ok(bundle)
+}
+
+/// Pack the commits reachable from `to` but not from `from` into `file`.
+/// Returns how many commits and objects it holds.
+fn create_bundle(file: &Path, range: &str) -> Result<(usize, usize)> {
+    let (from, to) = match range.split_once("..") {
+        Some((from, to)) => (Some(from), if to.is_empty() { "HEAD" } else { to }),
+        None => (None, range),
+    };
+
+    let tip = resolve_rev(to)?;
+    let name = match (to, read_head()?) {
+        ("HEAD", Head::Branch(branch)) => branch,
+        (to, _) if valid_ref_name(to) && branch_tip(to)?.is_some() => to.to_string(),
+        _ => "HEAD".to_string(),
+    };
+
+    let exclude = match from {
+        Some(from) => history(&[resolve_rev(from)?])?,
+        None => Vec::new(),
+    };
+    let excluded: Vec<&String> = exclude.iter().map(|(id, _)| id).collect();
+
+    let mut bundle = Bundle { refs: vec![(name, tip.clone())], commits: Vec::new(), objects: BTreeMap::new() };
+
+    for (id, meta) in history(&[tip])? {
+        if excluded.contains(&&id) {
+            continue;
+        }
+        if let Err(problem) = check_meta(&id, &meta)? {
+            return Err(ScmError::Verification(format!("Commit {} failed verification: {problem}", short_id(&id))));
+        }
+
+        for hash in meta.file_hashes.values() {
+            // The receiver already has whatever the excluded commits hold
+            let known = exclude.iter().any(|(_, m)| m.file_hashes.values().any(|h| h == hash));
+            if !known && !bundle.objects.contains_key(hash) {
+                bundle.objects.insert(hash.clone(), read_object(hash)?);
+            }
+        }
+
+        let raw = fs::read_to_string(commits_dir().join(&id).join("meta.json"))?;
+        bundle.commits.push((id, raw, meta));
+    }
+
+    if bundle.commits.is_empty() {
+        return Err(ScmError::NotFound(format!("No commits in {range}.")));
+    }
+
+    fs::write(file, write_bundle(&bundle))?;
+    Ok((bundle.commits.len(), bundle.objects.len()))
+}
+
+/// What importing a bundle did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unbundled {
+    /// How many of its commits were new here.
+    pub received: usize,
+    /// The refs the bundle carries, in its order.
+    pub refs: Vec<(String, ObjectId)>,
+    /// The refs that became new branches. Branches that already existed
+    /// are left for the user to merge.
+    pub created: Vec<String>,
+}
+
+fn unbundle(file: &Path) -> Result<Unbundled> {
+    let bundle = read_bundle(&fs::read(file)?)?;
+
+    let incoming: Vec<(String, String, CommitMeta)> = bundle
+        .commits
+        .into_iter()
+        .filter(|(id, _, _)| !commits_dir().join(id).join("meta.json").exists())
+        .collect();
+
+    for (id, _, _) in &incoming {
+        if !is_hash_like(id) {
+            return Err(ScmError::Verification(format!("Rejected: invalid commit ID {id}")));
+        }
+    }
+
+    // History the bundle leaves out must already be here
+    for (_, _, meta) in &incoming {
+        for parent in &meta.parents {
+            let bundled = incoming.iter().any(|(id, _, _)| id == parent);
+            if !bundled && !commits_dir().join(parent).join("meta.json").exists() {
+                return Err(ScmError::NotFound(format!("Missing prerequisite commit {}; import the earlier history first.", short_id(parent))));
+            }
+        }
+    }
+
+    let received = match import_commits(&incoming, |hash| {
+        bundle.objects.get(hash).cloned().ok_or_else(|| {
+            ScmError::Corrupt(format!("Bundle is missing object {hash}"))
+        })
+    })? {
+        Ok(n) => n,
+        Err(problem) => {
+            return Err(ScmError::Verification(format!("Rejected: {problem}")));
+        }
+    };
+
+    let mut created = Vec::new();
+    for (name, id) in &bundle.refs {
+        let creatable = name != "HEAD" && valid_ref_name(name) && branch_tip(name)?.is_none();
+        if !creatable || !commits_dir().join(id).join("meta.json").exists() {
+            continue;
+        }
+
+        if matches!(read_head()?, Head::Branch(ref b) if b == name) {
+            let meta = load_meta(id)?;
+            restore_from(&meta)?;
+            save_index(&meta.snapshot())?;
+        }
+        set_branch(name, id)?;
+        created.push(name.clone());
+    }
+
+    let refs = bundle.refs.into_iter().map(|(name, id)| (name, ObjectId(id))).collect();
+    Ok(Unbundled { received, refs, created })
+}
+
+// ---------------------------------------------------------
+// REPOSITORY CHECK
+// ---------------------------------------------------------
+
+/// Result of checking every commit reachable from a branch, tag or HEAD.
+/// Each check gives the trusted signer's name or what is wrong.
+#[derive(Debug)]
+pub struct FsckReport {
+    /// Annotated tags, and tags that could not be read.
+    pub tags: Vec<(String, Result<String, String>)>,
+    pub commits: Vec<(ObjectId, Result<String, String>)>,
+    /// Stored commits that nothing reaches.
+    pub dangling: Vec<ObjectId>,
+}
+
+impl FsckReport {
+    pub fn failures(&self) -> usize {
+        let tags = self.tags.iter().filter(|(_, r)| r.is_err()).count();
+        tags + self.commits.iter().filter(|(_, r)| r.is_err()).count()
+    }
+}
+
+/// Check stored objects, tree objects, Merkle root, commit ID, signature
+/// and parent links of every reachable commit, and every annotated tag.
+fn check_repository() -> Result<FsckReport> {
+    let mut report = FsckReport { tags: Vec::new(), commits: Vec::new(), dangling: Vec::new() };
+
+    let mut starts = Vec::new();
+    if let Some(id) = head_commit()? {
+        starts.push(id);
+    }
+    if heads_dir().exists() {
+        for entry in fs::read_dir(heads_dir())? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(id) = branch_tip(&name)? {
+                if !starts.contains(&id) {
+                    starts.push(id);
+                }
+            }
+        }
+    }
+
+    for name in tag_names()? {
+        match read_tag(&name) {
+            Ok(Some(tag)) => {
+                if tag.annotated() {
+                    report.tags.push((name.clone(), check_tag(&tag, &name)?));
+                }
+                if !starts.contains(&tag.target) {
+                    starts.push(tag.target);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => report.tags.push((name, Err(e.to_string()))),
+        }
+    }
+
+    // Each commit is loaded once. One that cannot be is reported against
+    // its children (or itself, for a start) and never queued.
+    let mut queue = CommitQueue::new();
+    let mut unreadable: HashSet<String> = HashSet::new();
+    for id in starts {
+        if queue.mark(&id) {
+            match load_meta(&id) {
+                Ok(meta) => queue.push(id, meta),
+                Err(e) => report.commits.push((ObjectId(id), Err(format!("cannot read metadata: {e}")))),
+            }
+        }
+    }
+
+    while let Some((id, meta)) = queue.pop() {
+        let result = match check_meta(&id, &meta) {
+            Ok(Ok(signer)) => match commit_parents(&id, &meta) {
+                Ok(parents) => {
+                    let mut missing = None;
+                    for p in parents {
+                        if unreadable.contains(&p) {
+                            missing.get_or_insert(p);
+                        } else if !queue.seen.contains(&p) {
+                            match load_meta(&p) {
+                                Ok(parent_meta) => {
+                                    queue.mark(&p);
+                                    queue.push(p, parent_meta);
+                                }
+                                Err(_) => {
+                                    unreadable.insert(p.clone());
+                                    missing.get_or_insert(p);
+                                }
+                            }
+                        }
+                    }
+                    match missing {
+                        Some(p) => Err(format!("parent {p} is missing or unreadable")),
+                        None => Ok(signer),
+                    }
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            Ok(Err(problem)) => Err(problem),
+            Err(e) => Err(e.to_string()),
+        };
+        report.commits.push((ObjectId(id), result));
+    }
+
+    if commits_dir().exists() {
+        for entry in fs::read_dir(commits_dir())? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if !queue.seen.contains(&name) {
+                report.dangling.push(ObjectId(name));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+// ---------------------------------------------------------
+// COMMITTING
+// ---------------------------------------------------------
+
+/// Commit the index on top of HEAD, and of MERGE_HEAD when concluding a
+/// merge. None if there is nothing to commit.
+fn commit_index(message: &str) -> Result<Option<String>> {
+    let conflicts = load_conflicts()?;
+    if !conflicts.is_empty() {
+        let list: String = conflicts.iter().map(|path| format!("\n  {path}")).collect();
+        return Err(ScmError::Conflict(format!(
+            "Unresolved merge conflicts; fix them and `scm add` each file:{list}"
+        )));
+    }
+
+    let staged = load_index()?;
+    let mut parents: Vec<String> = head_commit()?.into_iter().collect();
+    let head = match parents.first() {
+        Some(id) => load_meta(id)?.snapshot(),
+        None => BTreeMap::new(),
+    };
+    if merge_head_path().exists() {
+        parents.push(fs::read_to_string(merge_head_path())?.trim().to_string());
+    } else if head == staged {
+        return Ok(None);
+    }
+
+    let id = create_commit(staged, parents, message)?;
+    clear_merge_state()?;
+    Ok(Some(id))
+}
+
+/// Move HEAD back to its first parent; a branch whose only commit this is
+/// is left without commits. Returns the commit taken off, which stays in
+/// the store and can still be checked out by ID.
+fn reset_head() -> Result<String> {
+    let Some(head) = head_commit()? else {
+        return Err(ScmError::NotFound("No commits to remove.".into()));
+    };
+
+    let parents = commit_parents(&head, &load_meta(&head)?)?;
+    match parents.first() {
+        Some(parent) => update_head(parent)?,
+        None => match read_head()? {
+            Head::Branch(name) => fs::remove_file(heads_dir().join(name))?,
+            Head::Detached(_) => {
+                return Err(ScmError::NotFound("No parent commit to reset to.".into()));
+            }
+        },
+    }
+    Ok(head)
+}
+
+/// Sign and store a commit of `snapshot`, then advance HEAD to it.
+fn create_commit(snapshot: Snapshot, parents: Vec<String>, message: &str) -> Result<String> {
+    let meta = compute_meta(&snapshot, parents, message)?;
+    let id = commit_id(&meta);
+
+    // Objects are already stored; the metadata makes the commit exist and
+    // the ref update makes it reachable
+    store_commit(&id, &serde_json::to_string_pretty(&meta).unwrap())?;
+    update_head(&id)?;
+    save_index(&meta.snapshot())?;
+
+    Ok(id)
+}
+
+// ---------------------------------------------------------
+// REPOSITORY
+// ---------------------------------------------------------
+//
+// The library's entry point. A Repository holds the repository lock for
+// as long as it lives, as one run of the scm command does, and each method
+// runs with the thread's context pointed at its root (see PATH HELPERS).
+
+/// A commit ID or the SHA-256 of a stored object, in lowercase hex.
+/// Commits from before content-derived IDs have six-digit IDs instead.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId(String);
+
+impl ObjectId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Abbreviated form for display.
+    pub fn short(&self) -> &str {
+        short_id(&self.0)
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::str::FromStr for ObjectId {
+    type Err = ScmError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+            return Err(ScmError::Usage(format!("Not an object ID: {s}")));
+        }
+        Ok(ObjectId(s.to_string()))
+    }
+}
+
+impl AsRef<str> for ObjectId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Who signs and authors commits. Anything left unset is found the way
+/// the scm command finds it: the signing key in $SCM_HOME or the user
+/// config directory, the passphrase from SCM_PASSPHRASE,
+/// SCM_PASSPHRASE_FILE or a prompt, and the author from SCM_AUTHOR_NAME,
+/// user.name or the login name.
+#[derive(Clone, Default)]
+pub struct Identity {
+    /// Directory holding signing_key and signing_key.pub.
+    pub key_dir: Option<PathBuf>,
+    pub passphrase: Option<String>,
+    /// "Name <email>" or just a name.
+    pub author: Option<String>,
+}
+
+// By hand, so the passphrase never ends up in logs or panic messages
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("key_dir", &self.key_dir)
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "<redacted>"))
+            .field("author", &self.author)
+            .finish()
+    }
+}
+
+/// An open, locked repository.
+pub struct Repository {
+    root: PathBuf,
+    identity: Identity,
+    notices: Mutex<Vec<Notice>>,
+    _lock: RepoLock,
+}
+
+impl Repository {
+    /// Create a repository in `path`, making the directory if needed, and
+    /// open it. An existing repository there is opened as it is.
+    pub fn init(path: impl AsRef<Path>, identity: Identity) -> Result<Repository> {
+        let root = std::path::absolute(path)?;
+        fs::create_dir_all(&root)?;
+        if !root.join(".scm").is_dir() {
+            with_context(&root, &identity, ensure_repo)?;
+        }
+        Repository::open(root, identity)
+    }
+
+    /// Open the repository at or above `path`.
+    pub fn open(path: impl AsRef<Path>, identity: Identity) -> Result<Repository> {
+        let root = find_repo_root(&std::path::absolute(path)?).ok_or(ScmError::NotARepo)?;
+        let (lock, notices) = collect_notices(|| with_context(&root, &identity, open_repo));
+        Ok(Repository { root, identity, notices: Mutex::new(notices), _lock: lock? })
+    }
+
+    /// Clone the repository at `src` (its working tree or its .scm
+    /// directory) into `dest`, which must be missing or empty, and open
+    /// the clone. Every commit is verified on the way in.
+    pub fn clone_from(src: impl AsRef<Path>, dest: impl AsRef<Path>, identity: Identity) -> Result<Repository> {
+        let remote = remote_scm_dir(src.as_ref())?;
+        let dest = std::path::absolute(dest)?;
+
+        if dest.exists() && fs::read_dir(&dest)?.next().is_some() {
+            return Err(ScmError::Refused(format!("Destination {} already exists and is not empty.", dest.display())));
+        }
+        fs::create_dir_all(dest.join(".scm"))?;
+
+        let repo = Repository::open(&dest, identity)?;
+        repo.enter(|| clone_into(&remote))?;
+        Ok(repo)
+    }
+
+    /// The working tree's top directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Run `f` with this repository as the thread's context, keeping the
+    /// notices it records.
+    fn enter<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let (result, notices) = collect_notices(|| with_context(&self.root, &self.identity, f));
+        self.notices.lock().unwrap().extend(notices);
+        result
+    }
+
+    /// What happened on the side of the calls made so far, such as a
+    /// signing key being generated, oldest first. Each is returned once.
+    pub fn take_notices(&self) -> Vec<Notice> {
+        std::mem::take(&mut *self.notices.lock().unwrap())
+    }
+
+    /// The commit HEAD points at, if any.
+    pub fn head(&self) -> Result<Option<ObjectId>> {
+        self.enter(|| Ok(head_commit()?.map(ObjectId)))
+    }
+
+    /// The branch or commit HEAD points at.
+    pub fn head_ref(&self) -> Result<Head> {
+        self.enter(read_head)
+    }
+
+    /// Resolve "HEAD", a branch or tag name, or a (prefix of a) commit ID.
+    pub fn resolve(&self, rev: &str) -> Result<ObjectId> {
+        self.enter(|| resolve_rev(rev).map(ObjectId))
+    }
+
+    /// Every branch name, sorted.
+    pub fn branches(&self) -> Result<Vec<String>> {
+        self.enter(branch_names)
+    }
+
+    /// Create branch `name` at `rev`, or at HEAD if None. Returns its tip.
+    pub fn create_branch(&self, name: &str, rev: Option<&str>) -> Result<ObjectId> {
+        self.enter(|| {
+            ensure_repo()?;
+            create_branch(name, rev).map(ObjectId)
+        })
+    }
+
+    /// Delete a branch other than the checked-out one.
+    pub fn delete_branch(&self, name: &str) -> Result<()> {
+        self.enter(|| delete_branch(name))
+    }
+
+    /// Switch to a branch or commit once it has been verified, bringing the
+    /// working tree and index to it as `options` allow. A merge or revert
+    /// in progress is refused unless `options.force`, and then dropped.
+    /// Returns where HEAD now points.
+    pub fn checkout(&self, spec: &str, options: RestoreOptions) -> Result<Head> {
+        self.enter(|| {
+            ensure_repo()?;
+            checkout_rev(spec, options)
+        })
+    }
+
+    /// Create branch `name` at HEAD and switch to it, keeping the working
+    /// tree and index.
+    pub fn checkout_new_branch(&self, name: &str) -> Result<()> {
+        self.enter(|| {
+            ensure_repo()?;
+            checkout_new_branch(name)
+        })
+    }
+
+    /// Merge a branch or commit into HEAD (see MergeOutcome). Refused with
+    /// uncommitted changes or while another merge is in progress.
+    pub fn merge(&self, spec: &str, no_ff: bool) -> Result<MergeOutcome> {
+        self.enter(|| {
+            ensure_repo()?;
+            merge_rev(spec, no_ff)
+        })
+    }
+
+    /// Commit the reverse of a commit's changes on top of HEAD.
+    pub fn revert(&self, rev: &str) -> Result<RevertOutcome> {
+        self.enter(|| {
+            ensure_repo()?;
+            revert_commit(rev)
+        })
+    }
+
+    /// Throw away a merge or revert stopped by conflicts and return to
+    /// HEAD.
+    pub fn abort_merge(&self) -> Result<()> {
+        self.enter(abort_in_progress)
+    }
+
+    /// The commit message prepared by a merge or revert that stopped for
+    /// conflicts, if one is in progress.
+    pub fn merge_message(&self) -> Result<Option<String>> {
+        self.enter(|| match fs::read_to_string(merge_msg_path()) {
+            Ok(message) => Ok(Some(message)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    /// Move HEAD back to its first parent. Returns the commit taken off,
+    /// which stays in the store.
+    pub fn reset(&self) -> Result<ObjectId> {
+        self.enter(|| reset_head().map(ObjectId))
+    }
+
+    /// Every tag, sorted by name.
+    pub fn tags(&self) -> Result<Vec<Tag>> {
+        self.enter(list_tags)
+    }
+
+    /// Tag `rev` as `name`, signing the tag when it has a message. Returns
+    /// the tagged commit.
+    pub fn create_tag(&self, name: &str, rev: &str, message: Option<&str>) -> Result<ObjectId> {
+        self.enter(|| {
+            ensure_repo()?;
+            create_tag(name, rev, message).map(ObjectId)
+        })
+    }
+
+    pub fn delete_tag(&self, name: &str) -> Result<()> {
+        self.enter(|| delete_tag(name))
+    }
+
+    /// Check an annotated tag's signature and the commit it names. Returns
+    /// the trusted signer and the commit.
+    pub fn verify_tag(&self, name: &str) -> Result<(String, ObjectId)> {
+        self.enter(|| verify_tag(name).map(|(signer, id)| (signer, ObjectId(id))))
+    }
+
+    /// Send the checked-out branch to the repository at `path`, which
+    /// verifies it against its own trusted keys. Unless `force`, only a
+    /// fast-forward is accepted.
+    pub fn push(&self, path: impl AsRef<Path>, force: bool) -> Result<TransferOutcome> {
+        self.enter(|| {
+            ensure_repo()?;
+            push_to(path.as_ref(), force)
+        })
+    }
+
+    /// Fetch the branch of the checked-out branch's name from the
+    /// repository at `path` and check it out. Unless `force`, only a
+    /// fast-forward is accepted.
+    pub fn pull(&self, path: impl AsRef<Path>, force: bool) -> Result<TransferOutcome> {
+        self.enter(|| {
+            ensure_repo()?;
+            pull_from(path.as_ref(), force)
+        })
+    }
+
+    /// Write the commits in `range` ("<rev>" or "<from>..<to>") to a
+    /// bundle file. Returns how many commits and objects it holds.
+    pub fn create_bundle(&self, file: impl AsRef<Path>, range: &str) -> Result<(usize, usize)> {
+        self.enter(|| {
+            ensure_repo()?;
+            create_bundle(file.as_ref(), range)
+        })
+    }
+
+    /// Import a bundle's commits once they have been verified, creating
+    /// the branches it carries that don't exist here yet.
+    pub fn unbundle(&self, file: impl AsRef<Path>) -> Result<Unbundled> {
+        self.enter(|| {
+            ensure_repo()?;
+            unbundle(file.as_ref())
+        })
+    }
+
+    /// What is staged, changed and untracked.
+    pub fn status(&self) -> Result<Status> {
+        self.enter(|| {
+            ensure_repo()?;
+            working_status()
+        })
+    }
+
+    /// Whether `path` (a repository path) is ignored, and by which rule.
+    pub fn check_ignore(&self, path: &str, is_dir: bool) -> Result<IgnoreStatus> {
+        self.enter(|| ignore_status(path, is_dir))
+    }
+
+    /// Every setting in .scm/config.
+    pub fn config(&self) -> Result<BTreeMap<String, String>> {
+        self.enter(|| {
+            ensure_repo()?;
+            load_config()
+        })
+    }
+
+    pub fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        self.enter(|| {
+            ensure_repo()?;
+            let mut config = load_config()?;
+            config.insert(key.to_string(), value.to_string());
+            save_config(&config)
+        })
+    }
+
+    /// The hex public key commits are signed with, generating the key
+    /// pair if there is none yet.
+    pub fn public_key(&self) -> Result<String> {
+        self.enter(|| {
+            ensure_repo()?;
+            match local_public_key()? {
+                Some(public_key) => Ok(public_key),
+                None => Ok(to_hex(load_or_generate_signing_key()?.verifying_key().as_bytes())),
+            }
+        })
+    }
+
+    /// Copy the signing key, still encrypted, to `file`.
+    pub fn export_key(&self, file: impl AsRef<Path>) -> Result<()> {
+        self.enter(|| export_signing_key(file.as_ref()))
+    }
+
+    /// Install an exported or raw hex signing key, replacing an existing
+    /// one only if `force`. Returns its public key.
+    pub fn import_key(&self, file: impl AsRef<Path>, force: bool) -> Result<String> {
+        self.enter(|| {
+            ensure_repo()?;
+            import_signing_key(file.as_ref(), force)
+        })
+    }
+
+    /// The keys whose signatures this repository accepts, revoked ones
+    /// included.
+    pub fn trusted_keys(&self) -> Result<Vec<TrustedKey>> {
+        self.enter(|| {
+            ensure_repo()?;
+            load_trusted_keys()
+        })
+    }
+
+    /// Trust a hex public key under `name`.
+    pub fn trust_key(&self, name: &str, public_key: &str) -> Result<()> {
+        self.enter(|| {
+            ensure_repo()?;
+            trust_key(name, public_key)
+        })
+    }
+
+    /// Revoke the trusted keys with this name or public key. Returns them.
+    pub fn revoke_key(&self, who: &str) -> Result<Vec<TrustedKey>> {
+        self.enter(|| {
+            ensure_repo()?;
+            revoke_key(who)
+        })
+    }
+
+    /// Prove that `path` is part of commit `rev`, once the commit has been
+    /// verified.
+    pub fn prove(&self, rev: &str, path: &str) -> Result<InclusionProof> {
+        self.enter(|| {
+            let id = resolve_rev(rev)?;
+            let meta = load_meta(&id)?;
+            verify_meta(&id, &meta)?;
+            build_proof(&id, &meta, path)
+        })
+    }
+
+    /// Check an inclusion proof against a file's contents, trusting this
+    /// repository's keys. Returns the signer.
+    pub fn verify_proof(&self, proof: &InclusionProof, data: &[u8]) -> Result<String> {
+        self.enter(|| check_proof(proof, data, &load_trusted_keys()?)?.map_err(ScmError::Verification))
+    }
+
+    /// Rewrite every object compressed, and with `use_delta` as a delta
+    /// against a newer version of the same file where that is smaller.
+    pub fn gc(&self, use_delta: bool) -> Result<GcReport> {
+        self.enter(|| {
+            ensure_repo()?;
+            gc(use_delta)
+        })
+    }
+
+    /// Stage files or directories, given relative to the root with `/`
+    /// separators ("." for everything).
+    pub fn add(&self, paths: &[&str]) -> Result<()> {
+        self.enter(|| {
+            ensure_repo()?;
+            let keyed: Vec<(&str, String)> = paths.iter().map(|p| (*p, repo_key(p))).collect();
+            add_paths(&keyed)
+        })
+    }
+
+    /// Stop tracking files or directories and delete them from the
+    /// working tree, as `options` allow. Returns the paths removed.
+    pub fn rm(&self, paths: &[&str], options: RmOptions) -> Result<Vec<String>> {
+        self.enter(|| {
+            ensure_repo()?;
+            let keyed: Vec<(&str, String)> = paths.iter().map(|p| (*p, repo_key(p))).collect();
+            rm_paths(&keyed, options)
+        })
+    }
+
+    /// Sign and commit what is staged. None if it matches HEAD.
+    pub fn commit(&self, message: &str) -> Result<Option<ObjectId>> {
+        self.enter(|| {
+            ensure_repo()?;
+            Ok(commit_index(message)?.map(ObjectId))
+        })
+    }
+
+    /// The commit `rev` names.
+    pub fn commit_meta(&self, rev: &str) -> Result<CommitMeta> {
+        self.enter(|| load_meta(&resolve_rev(rev)?))
+    }
+
+    /// Every commit reachable from `rev` (HEAD if None), newest first.
+    pub fn log(&self, rev: Option<&str>) -> Result<Vec<(ObjectId, CommitMeta)>> {
+        self.enter(|| {
+            let start = match rev {
+                Some(rev) => resolve_rev(rev)?,
+                None => match head_commit()? {
+                    Some(id) => id,
+                    None => return Ok(Vec::new()),
+                },
+            };
+            Ok(history(&[start])?.into_iter().map(|(id, meta)| (ObjectId(id), meta)).collect())
+        })
+    }
+
+    /// Files that differ from `old` to `new`, or to the working tree if
+    /// `new` is None.
+    pub fn diff(&self, old: &str, new: Option<&str>) -> Result<Vec<FileDiff>> {
+        self.enter(|| {
+            let old = resolve_rev(old)?;
+            let new = new.map(resolve_rev).transpose()?;
+            diff_commits(&old, new.as_deref())
+        })
+    }
+
+    /// Check one commit's objects, Merkle root, ID, signature and signer.
+    /// Returns the trusted signer's name.
+    pub fn verify_commit(&self, rev: &str) -> Result<String> {
+        self.enter(|| {
+            let id = resolve_rev(rev)?;
+            verify_meta(&id, &load_meta(&id)?)
+        })
+    }
+
+    /// Check everything reachable from a branch, tag or HEAD.
+    pub fn verify(&self) -> Result<FsckReport> {
+        self.enter(check_repository)
+    }
+
+    /// Bring the working files under `paths` to their state as of `rev`,
+    /// once `rev` has been verified: write its files and remove tracked
+    /// files it lacks, as `options` allow. Returns how many files were
+    /// written and how many removed.
+    pub fn restore(&self, rev: &str, paths: &[&str], options: RestoreOptions) -> Result<(usize, usize)> {
+        self.enter(|| {
+            let keyed: Vec<(&str, String)> = paths.iter().map(|p| (*p, repo_key(p))).collect();
+            let (_, written, removed) = restore_paths(rev, &keyed, options)?;
+            Ok((written, removed))
+        })
+    }
+}
+
+/// Bring `paths` (as typed, with their keys) to their state as of `rev`
+/// once it has been verified (see checkout_snapshot). Each path must be in
+/// the commit or tracked now. Returns the commit and how many files were
+/// written and removed.
+fn restore_paths(rev: &str, paths: &[(&str, String)], options: RestoreOptions) -> Result<(String, usize, usize)> {
+    let id = resolve_rev(rev)?;
+    let meta = load_meta(&id)?;
+    verify_meta(&id, &meta)?;
+
+    // Tracked paths the commit lacks are removed, so they count as found
+    let target = meta.snapshot();
+    let index = load_index()?;
+    for (arg, key) in paths {
+        if !target.keys().chain(index.keys()).any(|k| path_covers(key, k)) {
+            return Err(ScmError::NotFound(format!("{arg}: not in commit {}", short_id(&id))));
+        }
+    }
+
+    let keys: Vec<String> = paths.iter().map(|(_, key)| key.clone()).collect();
+    let (written, removed) = checkout_snapshot(&target, &keys, options)?;
+    Ok((id, written, removed))
+}
+
+/// A root-relative path as a repository key ("" for the root itself).
+fn repo_key(path: &str) -> String {
+    path_key(&normalize(Path::new(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A fresh repository in a temporary directory, signing with its own key.
+    fn test_repo() -> (TempDir, Repository) {
+        let dir = TempDir::new().unwrap();
+        let identity = Identity {
+            key_dir: Some(dir.path().join("keys")),
+            passphrase: Some("test passphrase".into()),
+            author: Some("Test <test@example.com>".into()),
+        };
+        let repo = Repository::init(dir.path().join("work"), identity).unwrap();
+        (dir, repo)
+    }
+
+    fn glob(pat: &str, text: &str) -> bool {
+        glob_match(pat.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_wildcards_stop_at_slashes() {
+        assert!(glob("*.log", "debug.log"));
+        assert!(!glob("*.log", "logs/debug.log"));
+        assert!(glob("?.txt", "a.txt"));
+        assert!(!glob("?.txt", "ab.txt"));
+        assert!(!glob("a?b", "a/b"));
+        assert!(glob("", ""));
+        assert!(!glob("", "a"));
+    }
+
+    #[test]
+    fn glob_double_star_spans_directories() {
+        assert!(glob("**/build", "build"));
+        assert!(glob("**/build", "a/b/build"));
+        assert!(glob("src/**/*.rs", "src/main.rs"));
+        assert!(glob("src/**/*.rs", "src/a/b/lib.rs"));
+        assert!(glob("logs/**", "logs/a/b"));
+        assert!(!glob("src/**/*.rs", "lib/main.rs"));
+    }
+
+    #[test]
+    fn glob_classes_and_escapes() {
+        assert!(glob("file[0-9].txt", "file7.txt"));
+        assert!(!glob("file[0-9].txt", "filex.txt"));
+        assert!(glob("file[!0-9]", "filex"));
+        assert!(glob("file[^0-9]", "filex"));
+        assert!(!glob("file[!0-9]", "file3"));
+        assert!(glob("[]a]", "]"));
+        assert!(!glob("a[/]b", "a/b"));
+        // An unclosed bracket is a literal
+        assert!(glob("a[b", "a[b"));
+        assert!(glob("\\*", "*"));
+        assert!(!glob("\\*", "x"));
+    }
+
+    #[test]
+    fn character_class_parsing() {
+        let (matches, len) = match_class(b"a-cx]rest").unwrap();
+        assert_eq!(len, 5);
+        assert!(matches(b'b') && matches(b'x') && !matches(b'd'));
+
+        let (matches, len) = match_class(b"!]]").unwrap();
+        assert_eq!(len, 3);
+        assert!(!matches(b']') && matches(b'a'));
+
+        // A trailing '-' is literal
+        let (matches, _) = match_class(b"a-]").unwrap();
+        assert!(matches(b'-') && !matches(b'b'));
+
+        assert!(match_class(b"abc").is_none());
+    }
+
+    #[test]
+    fn merge_refuses_to_overwrite_an_untracked_file() {
+        let (_dir, repo) = test_repo();
+        fs::write(repo.root().join("a.txt"), "a\n").unwrap();
+        repo.add(&["a.txt"]).unwrap();
+        let id = repo.commit("first").unwrap().unwrap();
+        fs::write(repo.root().join("new.txt"), "mine\n").unwrap();
+
+        repo.enter(|| {
+            let base = load_meta(id.as_str())?.snapshot();
+            let mut theirs = base.clone();
+            let hash = write_object(b"theirs\n")?;
+            theirs.insert("new.txt".into(), Entry { hash, mode: MODE_FILE.into() });
+
+            let err = merge_snapshots(&base, &base, &theirs, "theirs").unwrap_err();
+            assert!(matches!(err, ScmError::Refused(_)), "{err}");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fs::read_to_string(repo.root().join("new.txt")).unwrap(), "mine\n");
+    }
+
+    #[test]
+    fn paths_outside_the_working_tree_are_rejected() {
+        let (_dir, repo) = test_repo();
+        fs::write(repo.root().join("a.txt"), "a\n").unwrap();
+        repo.add(&["a.txt"]).unwrap();
+        let id = repo.commit("first").unwrap().unwrap();
+
+        for bad in ["../x", "/abs", ".scm/HEAD", "d/./x", "d//x", ""] {
+            assert!(!valid_path_key(bad), "{bad:?}");
+        }
+        assert!(valid_path_key("d/.scmignore"));
+
+        repo.enter(|| {
+            let mut meta = load_meta(id.as_str())?;
+            let hash = meta.file_hashes["a.txt"].clone();
+            meta.file_hashes.insert("../x".into(), hash.clone());
+            assert!(check_meta(id.as_str(), &meta)?.is_err());
+
+            let tree = write_object(format!("{MODE_FILE} {hash} ..\n").as_bytes())?;
+            assert!(matches!(read_tree(META_VERSION_MODES, &tree), Err(ScmError::Corrupt(_))));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn identity_debug_hides_the_passphrase() {
+        let identity = Identity { passphrase: Some("hunter2".into()), ..Default::default() };
+        let shown = format!("{identity:?}");
+        assert!(!shown.contains("hunter2"), "{shown}");
+        assert!(shown.contains("<redacted>"), "{shown}");
+    }
+
+    #[test]
+    fn flat_commit_without_files_is_corrupt() {
+        let (_dir, repo) = test_repo();
+        fs::write(repo.root().join("a.txt"), "a\n").unwrap();
+        repo.add(&["a.txt"]).unwrap();
+        repo.commit("first").unwrap().unwrap();
+
+        let mut meta = repo.commit_meta("HEAD").unwrap();
+        meta.version = META_VERSION_FLAT;
+        meta.file_hashes.clear();
+        meta.file_modes.clear();
+        let result = repo.enter(|| check_meta("000001", &meta));
+        assert!(matches!(result, Err(ScmError::Corrupt(_))));
+    }
+
+    #[test]
+    fn proof_of_a_directory_as_a_file_is_rejected() {
+        let (_dir, repo) = test_repo();
+        fs::create_dir(repo.root().join("d")).unwrap();
+        fs::write(repo.root().join("d/a"), "a\n").unwrap();
+        fs::write(repo.root().join("d/b"), "b\n").unwrap();
+        repo.add(&["d"]).unwrap();
+        let id = repo.commit("first").unwrap().unwrap();
+
+        repo.enter(|| {
+            let meta = load_meta(id.as_str())?;
+            let mut proof = build_proof(id.as_str(), &meta, "d/a")?;
+            assert!(check_proof(&proof, b"a\n", &load_trusted_keys()?)?.is_ok());
+
+            // Claim the tree of d is a file whose contents are its Merkle node
+            let d = read_tree(meta.version, &meta.root)?.into_iter().find(|e| e.name == "d").unwrap();
+            let leaves: Vec<String> =
+                read_tree(meta.version, &d.hash)?.iter().map(|e| entry_leaf(meta.version, e)).collect();
+            let mut data = vec![MERKLE_NODE];
+            data.extend(from_hex(&leaves[0]).unwrap());
+            data.extend(from_hex(&leaves[1]).unwrap());
+            assert_eq!(to_hex(&Sha256::digest(&data)), d.hash);
+
+            proof.levels.remove(0);
+            proof.path = "d".into();
+            proof.file_hash = d.hash;
+            assert!(check_proof(&proof, &data, &load_trusted_keys()?)?.is_err());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let mut out = Vec::new();
+        push_varint(&mut out, 300);
+        assert_eq!(out, [0xac, 0x02]);
+
+        for n in [0, 1, 127, 128, 16_383, 16_384, usize::MAX] {
+            let mut out = Vec::new();
+            push_varint(&mut out, n);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos), Some(n));
+            assert_eq!(pos, out.len());
+        }
+
+        // Truncated, and longer than any usize
+        assert_eq!(read_varint(&[0x80], &mut 0), None);
+        assert_eq!(read_varint(&[0xff; 11], &mut 0), None);
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let base: Vec<u8> = (0..2000).map(|i| (i * 7 % 251) as u8).collect();
+        let mut target = base.clone();
+        target[500..510].copy_from_slice(b"0123456789");
+        target.splice(1200..1200, b"inserted".iter().copied());
+        target.truncate(1900);
+
+        for (base, target) in [(&base, &target), (&target, &base), (&base, &Vec::new()), (&Vec::new(), &base)] {
+            let delta = make_delta(base, target);
+            assert_eq!(apply_delta(base, &delta).as_ref(), Some(target));
+        }
+        assert!(make_delta(&base, &target).len() < 100);
+    }
+
+    #[test]
+    fn malformed_deltas_are_rejected() {
+        let base = b"0123456789";
+        let cases: [&[u8]; 6] = [
+            // No target length
+            &[],
+            // Copy past the end of the base
+            &[4, DELTA_COPY, 8, 4],
+            // Copy whose end overflows
+            &[2, DELTA_COPY, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 2],
+            // Insert longer than the delta
+            &[3, DELTA_INSERT, 5, b'a'],
+            // Unknown instruction
+            &[0, 7],
+            // Output shorter than the stated length
+            &[5, DELTA_INSERT, 3, b'a', b'b', b'c'],
+        ];
+        for delta in cases {
+            assert_eq!(apply_delta(base, delta), None, "{delta:?}");
+        }
+    }
+
+    #[test]
+    fn restore_reads_files_stored_as_deltas() {
+        let (_dir, repo) = test_repo();
+        let path = repo.root().join("big.txt");
+        let mut text: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        let mut versions = Vec::new();
+        for round in 0..3 {
+            text.push_str(&format!("round {round}\n"));
+            fs::write(&path, &text).unwrap();
+            repo.add(&["big.txt"]).unwrap();
+            let id = repo.commit(&format!("round {round}")).unwrap().unwrap();
+            versions.push((id, text.clone()));
+        }
+
+        let (_, deltas) = repo.enter(|| repack_objects(true)).unwrap();
+        assert_eq!(deltas, 2);
+        let stored_as_delta = |id: &ObjectId| {
+            let hash = repo.commit_meta(id.as_str()).unwrap().file_hashes["big.txt"].clone();
+            fs::read(repo.enter(|| Ok(object_path(&hash))).unwrap()).unwrap().starts_with(OBJECT_DELTA)
+        };
+        assert_eq!(versions.iter().filter(|(id, _)| stored_as_delta(id)).count(), 2);
+
+        let force = RestoreOptions { force: true, ..Default::default() };
+        for (id, text) in &versions {
+            repo.restore(id.as_str(), &["."], force).unwrap();
+            assert_eq!(&fs::read_to_string(&path).unwrap(), text);
+        }
+        assert_eq!(repo.verify().unwrap().failures(), 0);
+    }
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+        merge_lines(&split_lines(base), &split_lines(ours), &split_lines(theirs), "HEAD", "topic")
+    }
+
+    #[test]
+    fn merge_takes_each_sides_changes() {
+        let base = "a\nb\nc\nd\ne\n";
+        assert_eq!(merge(base, "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\n"), ("A\nb\nc\nd\nE\n".into(), false));
+        // Insertions and deletions on different sides
+        assert_eq!(merge(base, "a\nb\nx\nc\nd\ne\n", "a\nb\nc\ne\n"), ("a\nb\nx\nc\ne\n".into(), false));
+        // The same change on both sides is not a conflict
+        assert_eq!(merge(base, "a\nB\nc\nd\ne\n", "a\nB\nc\nd\ne\n"), ("a\nB\nc\nd\ne\n".into(), false));
+        assert_eq!(merge("", "", "new\n"), ("new\n".into(), false));
+    }
+
+    #[test]
+    fn merge_marks_conflicting_changes() {
+        let (out, conflict) = merge("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n");
+        assert!(conflict);
+        assert_eq!(out, "a\n<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\nc\n");
+
+        // A side without a final newline still gets its own marker line
+        let (out, conflict) = merge("a\n", "b", "c");
+        assert!(conflict);
+        assert_eq!(out, "<<<<<<< HEAD\nb\n=======\nc\n>>>>>>> topic\n");
+    }
+
+    fn diff(a: &str, b: &str, context: usize) -> String {
+        unified_diff(&split_lines(a), &split_lines(b), context)
+    }
+
+    #[test]
+    fn unified_diff_hunks() {
+        assert_eq!(diff("a\nb\nc\n", "a\nb\nc\n", 3), "");
+        assert_eq!(diff("a\nb\nc\n", "a\nB\nc\n", 3), "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n");
+        assert_eq!(diff("", "x\n", 3), "@@ -0,0 +1 @@\n+x\n");
+        assert_eq!(diff("x\n", "", 3), "@@ -1 +0,0 @@\n-x\n");
+
+        // Changes more than 2 * context lines apart get separate hunks
+        let old: String = (1..=10).map(|i| format!("{i}\n")).collect();
+        let new = old.replace("2\n", "x\n").replace("9\n", "y\n");
+        assert_eq!(diff(&old, &new, 1), "@@ -1,3 +1,3 @@\n 1\n-2\n+x\n 3\n@@ -8,3 +8,3 @@\n 8\n-9\n+y\n 10\n");
+        assert_eq!(diff(&old, &new, 4).matches("@@ -").count(), 1);
+    }
+
+    #[test]
+    fn unified_diff_marks_a_missing_final_newline() {
+        assert_eq!(
+            diff("a\nb", "a\nb\n", 3),
+            "@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n"
+        );
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let (_dir, repo) = test_repo();
+        fs::write(repo.root().join("a.txt"), "a\n").unwrap();
+        repo.add(&["a.txt"]).unwrap();
+        let id = repo.commit("first").unwrap().unwrap();
+        let meta = repo.commit_meta(id.as_str()).unwrap();
+
+        let raw = serde_json::to_string(&meta).unwrap();
+        let hash = meta.file_hashes["a.txt"].clone();
+        let bundle = Bundle {
+            refs: vec![("main".into(), id.to_string())],
+            commits: vec![(id.to_string(), raw.clone(), meta)],
+            // Payloads may hold newlines and arbitrary bytes
+            objects: BTreeMap::from([(hash.clone(), b"a\n\0\xff\n".to_vec())]),
+        };
+
+        let read = read_bundle(&write_bundle(&bundle)).unwrap();
+        assert_eq!(read.refs, bundle.refs);
+        assert_eq!(read.commits.len(), 1);
+        assert_eq!((read.commits[0].0.as_str(), read.commits[0].1.as_str()), (id.as_str(), raw.as_str()));
+        assert_eq!(read.objects, bundle.objects);
+    }
+
+    #[test]
+    fn malformed_bundles_are_rejected() {
+        // A bundle body with a correct checksum line, so parsing is reached
+        let seal = |body: &str| format!("{body}checksum {}\n", to_hex(&Sha256::digest(body.as_bytes()))).into_bytes();
+        let hash = "ab".repeat(32);
+
+        let good = seal(&format!("{BUNDLE_HEADER}object {hash} 3\nabc\n"));
+        assert_eq!(read_bundle(&good).unwrap().objects[&hash], b"abc");
+
+        let mut flipped = good.clone();
+        flipped[BUNDLE_HEADER.len() + 2] ^= 1;
+        let cases = [
+            Vec::new(),
+            flipped,
+            good[..good.len() - 1].to_vec(),
+            format!("{BUNDLE_HEADER}object {hash} 3\nabc\n").into_bytes(),
+            seal("scm-bundle 2\n"),
+            seal(&format!("{BUNDLE_HEADER}object {hash} 9\nabc\n")),
+            seal(&format!("{BUNDLE_HEADER}object {hash} 2\nabc\n")),
+            seal(&format!("{BUNDLE_HEADER}object {hash} x\nabc\n")),
+            seal(&format!("{BUNDLE_HEADER}commit {hash} 3\nabc\n")),
+            seal(&format!("{BUNDLE_HEADER}branch main {hash}\n")),
+            seal(&format!("{BUNDLE_HEADER}ref main\n")),
+        ];
+        for data in cases {
+            let result = read_bundle(&data);
+            assert!(matches!(result, Err(ScmError::Corrupt(_))), "{:?}", String::from_utf8_lossy(&data));
+        }
+    }
+
+    #[test]
+    fn commit_queue_pops_newest_first_and_ties_in_order() {
+        let (_dir, repo) = test_repo();
+        fs::write(repo.root().join("a.txt"), "a\n").unwrap();
+        repo.add(&["a.txt"]).unwrap();
+        repo.commit("first").unwrap().unwrap();
+        let base = repo.commit_meta("HEAD").unwrap();
+        let meta = |timestamp| CommitMeta { timestamp, ..base.clone() };
+
+        let mut queue = CommitQueue::new();
+        for (id, timestamp) in [("a", 5), ("b", 9), ("c", 5), ("d", 1)] {
+            assert!(queue.mark(id));
+            queue.push(id.into(), meta(timestamp));
+        }
+        assert!(!queue.mark("c"));
+
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop().map(|(id, _)| id)).collect();
+        assert_eq!(order, ["b", "a", "c", "d"]);
+    }
+
+    #[test]
+    fn fsck_checks_each_commit_once_and_reports_a_missing_parent() {
+        let (_dir, repo) = test_repo();
+        let mut ids = Vec::new();
+        for round in 0..5 {
+            fs::write(repo.root().join("a.txt"), format!("{round}\n")).unwrap();
+            repo.add(&["a.txt"]).unwrap();
+            ids.push(repo.commit(&format!("round {round}")).unwrap().unwrap());
+        }
+
+        let report = repo.verify().unwrap();
+        assert_eq!(report.commits.len(), 5);
+        assert_eq!(report.failures(), 0);
+
+        fs::remove_dir_all(repo.root().join(".scm/commits").join(ids[1].as_str())).unwrap();
+        let report = repo.verify().unwrap();
+        let failed: Vec<&ObjectId> = report.commits.iter().filter(|(_, r)| r.is_err()).map(|(id, _)| id).collect();
+        assert_eq!(failed, [&ids[2]]);
+        assert_eq!(report.commits.len(), 3);
+        assert_eq!(report.dangling, [ids[0].clone()]);
+    }
+}
@@ -0,0 +1,651 @@
+use std::fs;
+use std::path::Path;
+
+use scm::{
+    Change, Conflict, ConflictKind, Head, Identity, IgnoreStatus, MergeOutcome, Notice, Repository, RestoreOptions,
+    RevertOutcome, RmOptions, ScmError, TransferOutcome,
+};
+use tempfile::TempDir;
+
+/// A fresh repository in its own temporary directory, signing with a key
+/// kept alongside it.
+fn setup() -> (TempDir, Repository) {
+    let dir = TempDir::new().unwrap();
+    let identity = Identity {
+        key_dir: Some(dir.path().join("keys")),
+        passphrase: Some("test passphrase".into()),
+        author: Some("Test <test@example.com>".into()),
+    };
+    let repo = Repository::init(dir.path().join("work"), identity).unwrap();
+    (dir, repo)
+}
+
+fn write(repo: &Repository, path: &str, text: &str) {
+    let path = repo.root().join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, text).unwrap();
+}
+
+fn read(repo: &Repository, path: &str) -> String {
+    fs::read_to_string(repo.root().join(path)).unwrap()
+}
+
+fn corrupt_object(root: &Path, hash: &str) {
+    let path = root.join(".scm").join("objects").join(&hash[..2]).join(&hash[2..]);
+    fs::write(path, "tampered").unwrap();
+}
+
+#[test]
+fn commits_form_a_signed_history() {
+    let (_dir, repo) = setup();
+    assert!(repo.head().unwrap().is_none());
+    assert!(repo.log(None).unwrap().is_empty());
+
+    write(&repo, "a.txt", "one\n");
+    write(&repo, "src/b.txt", "two\n");
+    repo.add(&["."]).unwrap();
+    let first = repo.commit("first").unwrap().unwrap();
+
+    // The first signature generates the key, which the caller is told once
+    let keys = repo.root().parent().unwrap().join("keys");
+    assert_eq!(repo.take_notices(), [Notice::KeyGenerated(keys.join("signing_key"))]);
+    assert!(repo.take_notices().is_empty());
+
+    write(&repo, "a.txt", "one\nmore\n");
+    repo.add(&["a.txt"]).unwrap();
+    let second = repo.commit("second").unwrap().unwrap();
+
+    assert_eq!(repo.head().unwrap(), Some(second.clone()));
+    assert_eq!(repo.resolve(second.short()).unwrap(), second);
+
+    let log = repo.log(None).unwrap();
+    let ids: Vec<_> = log.iter().map(|(id, _)| id.clone()).collect();
+    assert_eq!(ids, vec![second.clone(), first.clone()]);
+
+    let (_, meta) = &log[0];
+    assert_eq!(meta.message, "second");
+    assert_eq!(meta.author, "Test <test@example.com>");
+    assert_eq!(meta.parents, vec![first.to_string()]);
+    assert_eq!(meta.file_hashes.keys().collect::<Vec<_>>(), ["a.txt", "src/b.txt"]);
+
+    assert_eq!(repo.verify_commit("HEAD").unwrap(), "Test <test@example.com>");
+    let report = repo.verify().unwrap();
+    assert_eq!(report.commits.len(), 2);
+    assert_eq!(report.failures(), 0);
+}
+
+#[test]
+fn committing_an_unchanged_index_does_nothing() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    repo.add(&["a.txt"]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    assert_eq!(repo.commit("again").unwrap(), None);
+    assert_eq!(repo.log(None).unwrap().len(), 1);
+}
+
+#[test]
+fn adding_a_missing_path_fails() {
+    let (_dir, repo) = setup();
+    let err = repo.add(&["nope.txt"]).unwrap_err();
+    assert!(matches!(err, ScmError::NotFound(_)), "{err}");
+}
+
+#[test]
+fn diff_between_commits_and_against_the_working_tree() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\ntwo\n");
+    write(&repo, "gone.txt", "bye\n");
+    repo.add(&["."]).unwrap();
+    let first = repo.commit("first").unwrap().unwrap();
+
+    write(&repo, "a.txt", "one\n2\n");
+    fs::remove_file(repo.root().join("gone.txt")).unwrap();
+    write(&repo, "new.txt", "hi\n");
+
+    let working = repo.diff("HEAD", None).unwrap();
+    let paths: Vec<&str> = working.iter().map(|d| d.path.as_str()).collect();
+    assert_eq!(paths, ["a.txt", "gone.txt", "new.txt"]);
+    assert_eq!(working[1].new, None);
+    assert_eq!(working[2].old, None);
+
+    let text = working[0].to_string();
+    assert!(text.contains("-two\n+2\n"), "{text}");
+
+    repo.add(&["."]).unwrap();
+    let second = repo.commit("second").unwrap().unwrap();
+    let committed = repo.diff(first.as_str(), Some(second.as_str())).unwrap();
+    assert_eq!(committed.len(), 3);
+    assert_eq!(committed[0].new.as_deref(), Some(&b"one\n2\n"[..]));
+
+    assert!(repo.diff("HEAD", None).unwrap().is_empty());
+}
+
+#[test]
+fn tracked_files_stay_visible_once_ignored() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.log", "one\n");
+    repo.add(&["a.log"]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    write(&repo, ".scmignore", "*.log\n");
+    repo.add(&[".scmignore"]).unwrap();
+    repo.commit("ignore logs").unwrap().unwrap();
+    assert!(repo.diff("HEAD", None).unwrap().is_empty());
+
+    write(&repo, "a.log", "two\n");
+    let diff = repo.diff("HEAD", None).unwrap();
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].new.as_deref(), Some(&b"two\n"[..]));
+}
+
+#[test]
+fn repository_directories_in_any_case_are_never_committed() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    write(&repo, ".SCM/notes", "x\n");
+    write(&repo, "sub/.Scm/notes", "y\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    let meta = repo.commit_meta("HEAD").unwrap();
+    assert_eq!(meta.file_hashes.keys().collect::<Vec<_>>(), ["a.txt"]);
+    assert_eq!(repo.verify().unwrap().failures(), 0);
+    assert!(matches!(repo.add(&[".SCM/notes"]), Err(ScmError::NotFound(_))));
+}
+
+#[cfg(unix)]
+#[test]
+fn file_names_with_line_breaks() {
+    let (_dir, repo) = setup();
+    write(&repo, "a\rb", "one\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+    assert_eq!(repo.verify().unwrap().failures(), 0);
+
+    write(&repo, "a\nb", "two\n");
+    assert!(matches!(repo.add(&["."]), Err(ScmError::Refused(_))));
+    assert_eq!(repo.commit("second").unwrap(), None);
+}
+
+#[test]
+fn rm_keeps_files_with_local_changes() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    write(&repo, "b.txt", "two\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    write(&repo, "a.txt", "one\nedited\n");
+    let err = repo.rm(&["."], RmOptions::default()).unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    assert_eq!(read(&repo, "a.txt"), "one\nedited\n");
+    assert!(repo.root().join("b.txt").exists());
+
+    // Staged but not committed is still a change
+    repo.add(&["a.txt"]).unwrap();
+    assert!(matches!(repo.rm(&["a.txt"], RmOptions::default()), Err(ScmError::Refused(_))));
+
+    assert_eq!(repo.rm(&["b.txt"], RmOptions::default()).unwrap(), ["b.txt"]);
+    assert!(!repo.root().join("b.txt").exists());
+    let cached = RmOptions { cached: true, ..Default::default() };
+    assert_eq!(repo.rm(&["a.txt"], cached).unwrap(), ["a.txt"]);
+    assert_eq!(read(&repo, "a.txt"), "one\nedited\n");
+
+    repo.add(&["a.txt"]).unwrap();
+    let force = RmOptions { force: true, ..Default::default() };
+    assert_eq!(repo.rm(&["a.txt"], force).unwrap(), ["a.txt"]);
+    assert!(!repo.root().join("a.txt").exists());
+}
+
+#[test]
+fn restore_brings_back_committed_contents() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "original\n");
+    write(&repo, "dir/b.txt", "b\n");
+    write(&repo, "dir/c.txt", "c\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    write(&repo, "a.txt", "edited\n");
+    fs::remove_dir_all(repo.root().join("dir")).unwrap();
+
+    // The edit is only thrown away when asked to
+    let err = repo.restore("HEAD", &["a.txt", "dir"], RestoreOptions::default()).unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    assert_eq!(read(&repo, "a.txt"), "edited\n");
+    assert!(!repo.root().join("dir").exists());
+
+    let force = RestoreOptions { force: true, ..Default::default() };
+    assert_eq!(repo.restore("HEAD", &["a.txt", "dir"], force).unwrap(), (3, 0));
+    assert_eq!(read(&repo, "a.txt"), "original\n");
+    assert_eq!(read(&repo, "dir/c.txt"), "c\n");
+
+    let err = repo.restore("HEAD", &["missing.txt"], RestoreOptions::default()).unwrap_err();
+    assert!(matches!(err, ScmError::NotFound(_)), "{err}");
+}
+
+#[test]
+fn restore_removes_files_added_since_the_commit() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    repo.add(&["a.txt"]).unwrap();
+    let first = repo.commit("first").unwrap().unwrap();
+
+    write(&repo, "a.txt", "two\n");
+    write(&repo, "new/b.txt", "b\n");
+    write(&repo, "new/c.txt", "c\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("second").unwrap().unwrap();
+    write(&repo, "untracked.txt", "mine\n");
+
+    let keep = RestoreOptions { keep: true, ..Default::default() };
+    repo.restore(first.as_str(), &["."], keep).unwrap();
+    assert_eq!(read(&repo, "a.txt"), "one\n");
+    assert_eq!(read(&repo, "new/b.txt"), "b\n");
+
+    // Tracked files the commit lacks go, along with their emptied
+    // directory; untracked ones stay
+    repo.restore(first.as_str(), &["."], RestoreOptions::default()).unwrap();
+    assert!(!repo.root().join("new").exists());
+    assert_eq!(read(&repo, "untracked.txt"), "mine\n");
+    let diff = repo.diff(first.as_str(), None).unwrap();
+    assert_eq!(diff.iter().map(|d| d.path.as_str()).collect::<Vec<_>>(), ["untracked.txt"]);
+}
+
+#[test]
+fn branches_tags_and_checkout() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    repo.add(&["a.txt"]).unwrap();
+    let first = repo.commit("first").unwrap().unwrap();
+    let Head::Branch(main) = repo.head_ref().unwrap() else { panic!("HEAD is detached") };
+
+    assert_eq!(repo.create_branch("topic", None).unwrap(), first);
+    let err = repo.create_branch("topic", None).unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    assert_eq!(repo.branches().unwrap(), [main.clone(), "topic".to_string()]);
+
+    repo.checkout("topic", RestoreOptions::default()).unwrap();
+    write(&repo, "a.txt", "two\n");
+    repo.add(&["a.txt"]).unwrap();
+    let second = repo.commit("second").unwrap().unwrap();
+
+    assert_eq!(repo.create_tag("v1", first.as_str(), None).unwrap(), first);
+    assert_eq!(repo.create_tag("v2", "HEAD", Some("release")).unwrap(), second);
+    let tags = repo.tags().unwrap();
+    assert_eq!(tags.iter().map(|t| (t.name.as_str(), &t.target)).collect::<Vec<_>>(), [("v1", &first), ("v2", &second)]);
+    assert_eq!(tags[1].message.as_deref(), Some("release"));
+    assert_eq!(repo.verify_tag("v2").unwrap(), ("Test <test@example.com>".to_string(), second.clone()));
+    assert!(matches!(repo.verify_tag("v1").unwrap_err(), ScmError::Usage(_)));
+
+    // Checking out a tag detaches HEAD
+    assert_eq!(repo.checkout("v1", RestoreOptions::default()).unwrap(), Head::Detached(first.clone()));
+    assert_eq!(read(&repo, "a.txt"), "one\n");
+
+    repo.checkout(&main, RestoreOptions::default()).unwrap();
+    repo.checkout_new_branch("other").unwrap();
+    assert_eq!(repo.head_ref().unwrap(), Head::Branch("other".into()));
+    let err = repo.delete_branch("other").unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    repo.delete_branch("topic").unwrap();
+    repo.delete_tag("v2").unwrap();
+    assert_eq!(repo.tags().unwrap().len(), 1);
+
+    // A reset commit is gone from every ref but not from the store
+    write(&repo, "a.txt", "three\n");
+    repo.add(&["a.txt"]).unwrap();
+    let third = repo.commit("third").unwrap().unwrap();
+    assert_eq!(repo.reset().unwrap(), third);
+    assert_eq!(repo.head().unwrap(), Some(first));
+    // ...and its contents stay in the working tree as changes to HEAD
+    let err = repo.checkout(second.as_str(), RestoreOptions::default()).unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    repo.checkout(second.as_str(), RestoreOptions { force: true, ..Default::default() }).unwrap();
+    assert_eq!(read(&repo, "a.txt"), "two\n");
+    repo.checkout(third.as_str(), RestoreOptions::default()).unwrap();
+    assert_eq!(read(&repo, "a.txt"), "three\n");
+}
+
+#[test]
+fn merge_and_revert_report_how_they_ended() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    write(&repo, "b.txt", "b\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+    let Head::Branch(main) = repo.head_ref().unwrap() else { panic!("HEAD is detached") };
+
+    repo.checkout_new_branch("topic").unwrap();
+    write(&repo, "a.txt", "topic\n");
+    repo.add(&["a.txt"]).unwrap();
+    let topic = repo.commit("topic").unwrap().unwrap();
+
+    repo.checkout(&main, RestoreOptions::default()).unwrap();
+    assert_eq!(repo.merge("topic", false).unwrap(), MergeOutcome::FastForward(topic.clone()));
+    assert_eq!(repo.merge("topic", false).unwrap(), MergeOutcome::UpToDate);
+
+    // Both sides change a.txt; b.txt is deleted on one and edited on the other
+    repo.checkout_new_branch("side").unwrap();
+    write(&repo, "a.txt", "side\n");
+    repo.rm(&["b.txt"], RmOptions::default()).unwrap();
+    repo.add(&["a.txt"]).unwrap();
+    repo.commit("side").unwrap().unwrap();
+    repo.checkout(&main, RestoreOptions::default()).unwrap();
+    write(&repo, "a.txt", "main\n");
+    write(&repo, "b.txt", "edited\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("main").unwrap().unwrap();
+
+    let conflict = |path: &str, kind| Conflict { path: path.into(), kind };
+    assert_eq!(
+        repo.merge("side", false).unwrap(),
+        MergeOutcome::Conflicts(vec![conflict("a.txt", ConflictKind::Content), conflict("b.txt", ConflictKind::ModifyDelete)])
+    );
+    assert_eq!(repo.merge_message().unwrap().as_deref(), Some(format!("Merge side into {main}").as_str()));
+    let err = repo.merge("side", false).unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    repo.abort_merge().unwrap();
+    assert!(repo.merge_message().unwrap().is_none());
+    assert_eq!(read(&repo, "a.txt"), "main\n");
+
+    // --no-ff records a merge even when HEAD could just move forward
+    repo.checkout_new_branch("ahead").unwrap();
+    write(&repo, "c.txt", "c\n");
+    repo.add(&["c.txt"]).unwrap();
+    repo.commit("ahead").unwrap().unwrap();
+    repo.checkout(&main, RestoreOptions::default()).unwrap();
+    let MergeOutcome::Merged(merge) = repo.merge("ahead", true).unwrap() else { panic!("no merge commit") };
+    assert_eq!(repo.head().unwrap(), Some(merge.clone()));
+    assert_eq!(read(&repo, "c.txt"), "c\n");
+
+    let RevertOutcome::Reverted(revert) = repo.revert("HEAD").unwrap() else { panic!("nothing reverted") };
+    assert!(!repo.root().join("c.txt").exists());
+    assert_eq!(repo.commit_meta(revert.as_str()).unwrap().parents, vec![merge.to_string()]);
+    assert_eq!(repo.revert(merge.as_str()).unwrap(), RevertOutcome::NothingToRevert);
+}
+
+#[test]
+fn history_moves_between_repositories() {
+    let (dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    repo.add(&["a.txt"]).unwrap();
+    let first = repo.commit("first").unwrap().unwrap();
+    repo.create_tag("v1", "HEAD", Some("release")).unwrap();
+
+    let identity = Identity {
+        key_dir: Some(dir.path().join("keys")),
+        passphrase: Some("test passphrase".into()),
+        author: Some("Test <test@example.com>".into()),
+    };
+    let clone = Repository::clone_from(repo.root(), dir.path().join("clone"), identity.clone()).unwrap();
+    assert_eq!(clone.head().unwrap(), Some(first.clone()));
+    assert_eq!(read(&clone, "a.txt"), "one\n");
+    assert_eq!(clone.verify_tag("v1").unwrap().1, first);
+    let err = Repository::clone_from(repo.root(), clone.root(), identity.clone()).err().unwrap();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+
+    write(&clone, "a.txt", "two\n");
+    clone.add(&["a.txt"]).unwrap();
+    let second = clone.commit("second").unwrap().unwrap();
+
+    // Push locks the destination, so the clone is closed while it pushes
+    let clone_root = clone.root().to_path_buf();
+    drop(clone);
+    let Head::Branch(main) = repo.head_ref().unwrap() else { panic!("HEAD is detached") };
+    let pulled = repo.pull(&clone_root, false).unwrap();
+    assert_eq!(pulled, TransferOutcome::Updated { branch: main, tip: second.clone(), received: 1 });
+    assert_eq!(read(&repo, "a.txt"), "two\n");
+    assert_eq!(repo.pull(&clone_root, false).unwrap(), TransferOutcome::UpToDate);
+    assert_eq!(repo.push(&clone_root, false).unwrap(), TransferOutcome::UpToDate);
+
+    // A bundle carries only the commits after its base
+    let bundle = dir.path().join("update.bundle");
+    assert_eq!(repo.create_bundle(&bundle, &format!("{first}..HEAD")).unwrap(), (1, 1));
+    let other = Repository::init(dir.path().join("other"), identity).unwrap();
+    let err = other.unbundle(&bundle).unwrap_err();
+    assert!(matches!(err, ScmError::NotFound(_)), "{err}");
+
+    repo.create_bundle(&bundle, "HEAD").unwrap();
+    let imported = other.unbundle(&bundle).unwrap();
+    assert_eq!(imported.received, 2);
+    assert_eq!(imported.refs.iter().map(|(_, id)| id).collect::<Vec<_>>(), [&second]);
+    assert_eq!(imported.created, imported.refs.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>());
+    assert_eq!(other.head().unwrap(), Some(second));
+}
+
+#[test]
+fn status_reports_staged_unstaged_and_untracked_files() {
+    let (_dir, repo) = setup();
+    write(&repo, ".scmignore", "*.log\n!keep.log\n");
+    write(&repo, "a.txt", "one\n");
+    write(&repo, "b.txt", "b\n");
+    repo.add(&["."]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+    assert!(repo.status().unwrap().is_clean());
+
+    write(&repo, "a.txt", "two\n");
+    write(&repo, "c.txt", "c\n");
+    repo.add(&["a.txt", "c.txt"]).unwrap();
+    repo.rm(&["b.txt"], RmOptions::default()).unwrap();
+    write(&repo, "c.txt", "changed\n");
+    write(&repo, "debug.log", "");
+    write(&repo, "keep.log", "");
+
+    let status = repo.status().unwrap();
+    assert_eq!(
+        status.staged,
+        [(Change::Modified, "a.txt".into()), (Change::Added, "c.txt".into()), (Change::Deleted, "b.txt".into())]
+    );
+    assert_eq!(status.unstaged, [(Change::Modified, "c.txt".to_string())]);
+    assert_eq!(status.untracked, ["keep.log"]);
+    assert!(!status.merging);
+
+    assert!(matches!(repo.check_ignore("debug.log", false).unwrap(), IgnoreStatus::Ignored { line: 1, .. }));
+    assert!(matches!(repo.check_ignore("keep.log", false).unwrap(), IgnoreStatus::Reincluded { line: 2, .. }));
+    assert_eq!(repo.check_ignore("a.txt", false).unwrap(), IgnoreStatus::NotIgnored);
+    assert_eq!(repo.check_ignore(".scm/HEAD", false).unwrap(), IgnoreStatus::Metadata);
+}
+
+#[test]
+fn keys_config_and_proofs() {
+    let (dir, repo) = setup();
+    write(&repo, "dir/a.txt", "one\n");
+    repo.add(&["dir"]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    repo.set_config("user.name", "Someone").unwrap();
+    assert_eq!(repo.config().unwrap().get("user.name").map(String::as_str), Some("Someone"));
+
+    let public_key = repo.public_key().unwrap();
+    let keys = repo.trusted_keys().unwrap();
+    assert_eq!(keys.iter().map(|k| k.public_key.as_str()).collect::<Vec<_>>(), [public_key.as_str()]);
+
+    let proof = repo.prove("HEAD", "dir/a.txt").unwrap();
+    assert_eq!(proof.path(), "dir/a.txt");
+    assert_eq!(repo.verify_proof(&proof, b"one\n").unwrap(), keys[0].name);
+    let err = repo.verify_proof(&proof, b"other\n").unwrap_err();
+    assert!(matches!(err, ScmError::Verification(_)), "{err}");
+
+    // Exported keys stay encrypted and are only replaced on request
+    let exported = dir.path().join("exported_key");
+    repo.export_key(&exported).unwrap();
+    let err = repo.import_key(&exported, false).unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    assert_eq!(repo.import_key(&exported, true).unwrap(), public_key);
+
+    let err = repo.trust_key("bad", "00").unwrap_err();
+    assert!(matches!(err, ScmError::Usage(_)), "{err}");
+    let revoked = repo.revoke_key(&public_key).unwrap();
+    assert!(revoked.iter().all(|k| k.revoked));
+    let err = repo.verify_proof(&proof, b"one\n").unwrap_err();
+    assert!(matches!(err, ScmError::Verification(_)), "{err}");
+    repo.trust_key("me", &public_key).unwrap();
+    assert_eq!(repo.verify_proof(&proof, b"one\n").unwrap(), "me");
+
+    let report = repo.gc(true).unwrap();
+    assert!(report.rewritten > 0);
+    assert_eq!(repo.verify().unwrap().failures(), 0);
+}
+
+#[test]
+fn tampered_objects_fail_verification() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    repo.add(&["a.txt"]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    let meta = repo.commit_meta("HEAD").unwrap();
+    corrupt_object(repo.root(), &meta.file_hashes["a.txt"]);
+
+    let report = repo.verify().unwrap();
+    assert_eq!(report.failures(), 1);
+    assert!(report.commits[0].1.is_err());
+
+    assert!(repo.verify_commit("HEAD").is_err());
+    assert!(repo.restore("HEAD", &["a.txt"], RestoreOptions::default()).is_err());
+}
+
+#[test]
+fn missing_objects_are_reported_as_corruption() {
+    let (_dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    repo.add(&["a.txt"]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    let hash = repo.commit_meta("HEAD").unwrap().file_hashes["a.txt"].clone();
+    fs::remove_file(repo.root().join(".scm").join("objects").join(&hash[..2]).join(&hash[2..])).unwrap();
+
+    let force = RestoreOptions { force: true, ..Default::default() };
+    let err = repo.restore("HEAD", &["a.txt"], force).unwrap_err();
+    assert!(matches!(err, ScmError::Corrupt(_)), "{err}");
+    assert_eq!(err.exit_code(), 5);
+}
+
+#[test]
+fn open_finds_the_repository_and_respects_the_lock() {
+    let (dir, repo) = setup();
+    write(&repo, "sub/a.txt", "one\n");
+
+    let err = Repository::open(repo.root().join("sub"), Identity::default()).err().unwrap();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+
+    let root = repo.root().to_path_buf();
+    drop(repo);
+    let reopened = Repository::open(root.join("sub"), Identity::default()).unwrap();
+    assert_eq!(reopened.root(), root);
+
+    let outside = dir.path().join("elsewhere");
+    fs::create_dir(&outside).unwrap();
+    let err = Repository::open(&outside, Identity::default()).err().unwrap();
+    assert!(matches!(err, ScmError::NotARepo), "{err}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn a_lock_left_by_a_crash_is_taken_over() {
+    let (_dir, repo) = setup();
+    let root = repo.root().to_path_buf();
+    drop(repo);
+
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead = child.id();
+    child.wait().unwrap();
+
+    let scm_dir = root.join(".scm");
+    fs::write(scm_dir.join("lock"), format!("{dead}\n")).unwrap();
+    fs::create_dir_all(scm_dir.join("tmp")).unwrap();
+    fs::write(scm_dir.join("tmp").join("leftover"), "partial").unwrap();
+
+    let repo = Repository::open(&root, Identity::default()).unwrap();
+    assert!(!scm_dir.join("tmp").join("leftover").exists());
+    assert_eq!(repo.take_notices(), [Notice::Recovered(1)]);
+    assert_eq!(fs::read_to_string(scm_dir.join("lock")).unwrap(), format!("{}\n", std::process::id()));
+    drop(repo);
+
+    // A live holder still keeps others out
+    fs::write(scm_dir.join("lock"), "1\n").unwrap();
+    let err = Repository::open(&root, Identity::default()).err().unwrap();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+}
+
+#[test]
+fn restore_swaps_files_and_directories() {
+    let (_dir, repo) = setup();
+    write(&repo, "d", "a file\n");
+    repo.add(&["d"]).unwrap();
+    let file = repo.commit("file").unwrap().unwrap();
+
+    fs::remove_file(repo.root().join("d")).unwrap();
+    write(&repo, "d/x", "inside\n");
+    repo.add(&["d", "d/x"]).unwrap();
+    let dir = repo.commit("dir").unwrap().unwrap();
+
+    repo.restore(file.as_str(), &["."], RestoreOptions::default()).unwrap();
+    assert_eq!(read(&repo, "d"), "a file\n");
+
+    // Committed, so the file is tracked as HEAD's and can give way again
+    repo.add(&["."]).unwrap();
+    repo.commit("file again").unwrap().unwrap();
+    repo.restore(dir.as_str(), &["."], RestoreOptions::default()).unwrap();
+    assert_eq!(read(&repo, "d/x"), "inside\n");
+
+    // An untracked file in the directory keeps it from being replaced
+    write(&repo, "d/mine", "keep me\n");
+    let err = repo.restore(file.as_str(), &["."], RestoreOptions::default()).unwrap_err();
+    assert!(matches!(err, ScmError::Refused(_)), "{err}");
+    assert_eq!(read(&repo, "d/x"), "inside\n");
+    assert_eq!(read(&repo, "d/mine"), "keep me\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn executable_bits_and_symlinks_survive_a_restore() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let (_dir, repo) = setup();
+    let script = repo.root().join("run.sh");
+    write(&repo, "run.sh", "#!/bin/sh\n");
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    symlink("run.sh", repo.root().join("link")).unwrap();
+    symlink("nowhere", repo.root().join("dangling")).unwrap();
+    repo.add(&["."]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    let meta = repo.commit_meta("HEAD").unwrap();
+    assert_eq!(meta.file_modes["run.sh"], "100755");
+    assert_eq!(meta.file_modes["link"], "120000");
+
+    // A mode change alone is a change
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o644)).unwrap();
+    let diff = repo.diff("HEAD", None).unwrap();
+    assert_eq!(diff.len(), 1);
+    assert!(diff[0].to_string().contains("old mode 100755\nnew mode 100644\n"), "{}", diff[0]);
+
+    fs::remove_file(repo.root().join("link")).unwrap();
+    fs::remove_file(repo.root().join("dangling")).unwrap();
+    let force = RestoreOptions { force: true, ..Default::default() };
+    assert_eq!(repo.restore("HEAD", &["."], force).unwrap(), (3, 0));
+
+    assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o111, 0o111);
+    assert_eq!(fs::read_link(repo.root().join("link")).unwrap(), Path::new("run.sh"));
+    assert_eq!(fs::read_link(repo.root().join("dangling")).unwrap(), Path::new("nowhere"));
+    assert!(repo.diff("HEAD", None).unwrap().is_empty());
+    assert_eq!(repo.verify().unwrap().failures(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn signing_key_is_private_from_the_start() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (dir, repo) = setup();
+    write(&repo, "a.txt", "one\n");
+    repo.add(&["a.txt"]).unwrap();
+    repo.commit("first").unwrap().unwrap();
+
+    let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&dir.path().join("keys")), 0o700);
+    assert_eq!(mode(&dir.path().join("keys").join("signing_key")), 0o600);
+}